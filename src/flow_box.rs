@@ -1,513 +1,1266 @@
 //! Defines fluid simulation logic
-use std::ops::{Add, Div, Mul, Sub};
+use std::f32::consts::PI;
+use std::ops::Range;
 
-use glam::Vec3;
+use glam::{Vec2, Vec3};
 use rayon::prelude::*;
 
+use super::grid::{
+    apply_body_forces, cfl, confine_vorticity, max_divergence, step_adaptive, Bound, Domain,
+    EdgeKind, Grid,
+};
+use super::obstacle::{polygon_contains, Obstacle};
+use super::pressure::{PressureSolver, SolveLimits, SolveReport};
+use super::real::{FieldValue, Real};
+
+/// Width and height of each cell in metres for grids which are not given a size
+pub(crate) const DEFAULT_CELL_SIZE: f32 = 0.01;
+
+/// Schemes for moving values along with the fluid
+#[derive(PartialEq, Clone, Copy)]
+pub enum AdvectionScheme {
+    // First order, stable but smears values out
+    SemiLagrangian,
+    // Corrects a semi lagrangian step using a backward step, second order
+    MacCormack,
+    // Back and forth error compensation, corrects the values before advecting them
+    Bfecc,
+}
+
+/// Methods for tracing back along the velocity field to where fluid came from
+#[derive(PartialEq, Clone, Copy)]
+pub enum Backtrace {
+    // Single step along the velocity of the cell
+    Euler,
+    // Second order, steps along the velocity found half way back
+    Midpoint,
+    // Third order Runge Kutta
+    Rk3,
+}
+
+impl Backtrace {
+    /// Returns the position fluid at p came from dt seconds ago, starting along k1 with
+    /// vel_at giving the velocity anywhere else, both in the units of p per second
+    pub(crate) fn trace<R, P, F>(&self, p: P, k1: P, dt: R, vel_at: F) -> P
+    where
+        R: Real,
+        P: FieldValue<R>,
+        F: Fn(P) -> P,
+    {
+        let c = R::from_f32;
+        match self {
+            Backtrace::Euler => p - k1 * dt,
+            Backtrace::Midpoint => p - vel_at(p - k1 * (c(0.5) * dt)) * dt,
+            Backtrace::Rk3 => {
+                let k2 = vel_at(p - k1 * (c(0.5) * dt));
+                let k3 = vel_at(p - k2 * (c(0.75) * dt));
+                p - (k1 * c(2.0) + k2 * c(3.0) + k3 * c(4.0)) * (dt / c(9.0))
+            }
+        }
+    }
+}
+
+/// Ways of interpolating values between cells when advecting them
+#[derive(PartialEq, Clone, Copy)]
+pub enum Interpolation {
+    // Blends the four surrounding cells
+    Bilinear,
+    // Catmull Rom spline over the sixteen surrounding cells, clamped to the nearest four
+    // so it stays sharp without ringing
+    MonotoneCubic,
+}
+
+/// Gravity vectors with a component along each axis of a grid
+pub trait Gravity: Copy + PartialEq {
+    /// Returns standard gravity of 9.8 m/s² pulling down the y axis
+    fn standard() -> Self;
+}
+impl Gravity for Vec2 {
+    fn standard() -> Self {
+        Vec2::new(0.0, 9.8)
+    }
+}
+impl Gravity for Vec3 {
+    fn standard() -> Self {
+        Vec3::new(0.0, 9.8, 0.0)
+    }
+}
+
 /// Represents fluid simulation behavior
+///
+/// Gravity is a Vec2 for FlowBox and MacFlowBox and a Vec3 for FlowBox3
 #[derive(PartialEq)]
-pub struct FluidParams {
+pub struct FluidParams<G = Vec2> {
+    // Kinematic viscosity in square metres per second, water is around 1e-6 and air 1.5e-5
     pub viscosity: f32,
+    // How quickly dye spreads through the fluid in square metres per second
     pub diffusion_rate: f32,
+    // How quickly heat spreads through the fluid in square metres per second
+    pub conductivity: f32,
     pub diffuse_iters: usize,
+    // Over relaxation factor for Gauss Seidel sweeps, 1 is plain Gauss Seidel and values
+    // up to 2 converge faster for stiff solves
+    pub sor_omega: f32,
+    // Diffusion stops early once the largest residual is this fraction of the largest value,
+    // zero always runs every one of diffuse_iters
+    pub diffuse_tolerance: f32,
     pub project_iters: usize,
-    pub gravity: f32,
+    pub pressure_solver: PressureSolver,
+    // Iterative pressure solvers stop once the largest residual is this fraction of the largest divergence
+    pub pressure_tolerance: f32,
+    // Upper limit on iterations for pressure solvers which stop on tolerance
+    pub pressure_max_iters: usize,
+    // Gravitational acceleration in metres per second squared, y grows downward. Unless
+    // uniform_gravity is set it only acts through the buoyancy terms below, so with both
+    // buoyancies at zero gravity has no effect
+    pub gravity: G,
+    // Whether gravity also pulls on the fluid as a whole. Hydrostatic pressure balances the
+    // pull unless edges hold fixed pressures at different heights, so fluid falls from one
+    // OUTLET, PRESSURE or CONVECTIVE_OUTLET to another below it and drains down along one at
+    // the side
+    pub uniform_gravity: bool,
+    // How strongly dye density pulls along gravity, negative values make dye rise
+    pub density_buoyancy: f32,
+    // How strongly fluid warmer than ambient rises against gravity
+    pub thermal_buoyancy: f32,
+    // Temperature of undisturbed fluid, new grids start at this temperature
+    pub ambient_temperature: f32,
+    // Strength of vorticity confinement which restores swirls lost to numerical dissipation,
+    // scaled by the cell size so the effect fades as the grid is refined
+    pub vorticity_confinement: f32,
+    pub advection: AdvectionScheme,
+    pub backtrace: Backtrace,
+    pub interpolation: Interpolation,
+    // Largest number of cells fluid may cross in one substep of step_adaptive
+    pub max_cfl: f32,
+    // Upper limit on substeps taken by step_adaptive, the last substep covers whatever is left
+    pub max_substeps: usize,
+    // How fluid moves along fixed solid cells, moving obstacles carry their own
+    pub solid_slip: Slip,
 }
-impl Default for FluidParams {
+impl<G: Gravity> Default for FluidParams<G> {
     fn default() -> Self {
         Self {
             viscosity: 0.00005,
             diffusion_rate: 0.00005,
+            conductivity: 0.00005,
             diffuse_iters: 3,
+            sor_omega: 1.0,
+            diffuse_tolerance: 0.0,
             project_iters: 5,
-            gravity: -9.8,
+            pressure_solver: PressureSolver::Relaxation,
+            pressure_tolerance: 1e-4,
+            pressure_max_iters: 500,
+            gravity: G::standard(),
+            uniform_gravity: false,
+            density_buoyancy: 0.0,
+            thermal_buoyancy: 0.0,
+            ambient_temperature: 0.0,
+            vorticity_confinement: 0.0,
+            advection: AdvectionScheme::SemiLagrangian,
+            backtrace: Backtrace::Euler,
+            interpolation: Interpolation::Bilinear,
+            max_cfl: 1.0,
+            max_substeps: 16,
+            solid_slip: Slip::NoSlip,
+        }
+    }
+}
+
+impl<G> FluidParams<G> {
+    pub(crate) fn diffuse_limits(&self) -> SolveLimits {
+        SolveLimits {
+            max_iters: self.diffuse_iters,
+            tolerance: self.diffuse_tolerance,
+        }
+    }
+    pub(crate) fn pressure_limits(&self) -> SolveLimits {
+        SolveLimits {
+            max_iters: match self.pressure_solver {
+                PressureSolver::Relaxation => self.project_iters,
+                _ => self.pressure_max_iters,
+            },
+            tolerance: self.pressure_tolerance,
+        }
+    }
+}
+
+/// Summary of the work done by a step and how accurate its solves were
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct StepReport {
+    // Iterations used by every diffusion solve
+    pub diffuse_iterations: usize,
+    // Largest relative residual left by any diffusion solve
+    pub diffuse_residual: f32,
+    // Iterations or cycles used by every pressure solve
+    pub pressure_iterations: usize,
+    // Relative residual left by the final pressure solve
+    pub pressure_residual: f32,
+    // Largest divergence per second left in any fluid cell by the final projection, as
+    // max_divergence measures it
+    pub max_divergence: f32,
+}
+impl StepReport {
+    pub(crate) fn add_diffuse(&mut self, report: SolveReport) {
+        self.diffuse_iterations += report.iterations;
+        self.diffuse_residual = self.diffuse_residual.max(report.residual);
+    }
+    pub(crate) fn add_pressure(&mut self, report: SolveReport) {
+        self.pressure_iterations += report.iterations;
+        self.pressure_residual = report.residual;
+    }
+    /// Adds up the work of a later step, keeping the worst residuals and divergence of either
+    pub fn merge(&mut self, other: StepReport) {
+        self.diffuse_iterations += other.diffuse_iterations;
+        self.diffuse_residual = self.diffuse_residual.max(other.diffuse_residual);
+        self.pressure_iterations += other.pressure_iterations;
+        self.pressure_residual = self.pressure_residual.max(other.pressure_residual);
+        self.max_divergence = self.max_divergence.max(other.max_divergence);
+    }
+}
+
+/// Shape of the inflow speed across an inlet
+pub enum Profile {
+    // The same speed across the whole opening
+    Uniform,
+    // Full speed in the middle falling to zero at the ends, like flow out of a long pipe
+    Parabolic,
+    // Scales the speed given the position across the opening from 0 to 1 and the time
+    // in seconds
+    Custom(Box<dyn Fn(f32, f32) -> f32 + Send + Sync>),
+}
+impl Profile {
+    /// Pulses a profile over time, the speed swings by amplitude either side of its
+    /// usual value frequency times a second
+    pub fn pulsatile(profile: Profile, amplitude: f32, frequency: f32) -> Self {
+        Profile::Custom(Box::new(move |s, t| {
+            profile.factor(s, t) * (1.0 + amplitude * (2.0 * PI * frequency * t).sin())
+        }))
+    }
+    /// Returns the fraction of full speed at a position across the opening and a time
+    pub fn factor(&self, s: f32, t: f32) -> f32 {
+        match self {
+            Profile::Uniform => 1.0,
+            Profile::Parabolic => 4.0 * s * (1.0 - s),
+            Profile::Custom(f) => f(s, t),
+        }
+    }
+}
+
+/// Flow entering the sim through an inlet
+pub struct Inlet {
+    // Full speed of the inflow in metres per second
+    pub speed: f32,
+    pub profile: Profile,
+    // Dye color held across the opening, None leaves density alone
+    pub density: Option<Vec3>,
+    // Temperature held across the opening, None leaves temperature alone
+    pub temperature: Option<f32>,
+}
+impl Inlet {
+    pub fn init(speed: f32) -> Self {
+        Inlet {
+            speed,
+            profile: Profile::Uniform,
+            density: None,
+            temperature: None,
         }
     }
 }
 
 /// Different boundary types for a fluid
+#[allow(non_camel_case_types)]
 pub enum BoundaryType {
-    // Inlet boundary: allows an inflow of fluid and gives the option to add in fluid density
-    INLET(f32, bool),
-    // Outlet boundary: allows fluid out
+    // Inlet boundary: allows an inflow of fluid and can carry in dye and heat
+    INLET(Inlet),
+    // Outlet boundary: allows fluid out, values leave with zero gradient at zero pressure
     OUTLET,
-    // Solid boundary: acts as walls constraining flow
-    SOLID,
+    // Convective outlet boundary: like an outlet but the edge is carried along by the flow
+    // so swirls pass out cleanly rather than being partly reflected
+    CONVECTIVE_OUTLET,
+    // Pressure boundary: an opening held at the given pressure divided by density in m²/s²,
+    // fluid flows in or out as the difference to the pressure inside drives it
+    PRESSURE(f32),
+    // Solid boundary: acts as walls constraining flow, fluid slips along it as given
+    SOLID(Slip),
+    // Moving wall boundary: a no-slip wall sliding along itself at the given speed in m/s,
+    // positive speeds move towards increasing x or y, a moving top wall drives a lid cavity
+    MOVING_WALL(f32),
+    // Periodic boundary: wraps around to the opposite edge so fluid leaving one side enters
+    // the other, as the base of both edges of an axis it wraps the whole axis and segments
+    // along them are ignored. A periodic base facing one which isn't, or a periodic segment
+    // on an axis that doesn't wrap, is rejected
+    PERIODIC,
+}
+
+/// How fluid moves along a solid surface
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Slip {
+    // Fluid sticks to the surface, like a viscous pipe wall
+    #[default]
+    NoSlip,
+    // Fluid slides freely along the surface, like a symmetry plane
+    FreeSlip,
+    // Fluid is held back by a friction coefficient, 0 slides freely and 1 sticks
+    Partial(f32),
 }
+impl Slip {
+    /// Returns the friction coefficient, 0 slides freely and 1 sticks
+    pub fn friction(&self) -> f32 {
+        match self {
+            Slip::NoSlip => 1.0,
+            Slip::FreeSlip => 0.0,
+            Slip::Partial(friction) => friction.clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// A run of cells along an edge with its own boundary type
+pub struct Segment {
+    // Cells along the edge counted from the top or left corner
+    pub cells: Range<usize>,
+    pub boundary: BoundaryType,
+}
+
+/// A damping layer inside an edge which relaxes the fluid towards a far field state,
+/// soaking up waves and swirls before they can reflect off the edge
+pub struct Sponge {
+    // Depth of the layer in cells
+    pub thickness: usize,
+    // Relaxation rate per second at the edge, fading smoothly to nothing at the inner side
+    pub strength: f32,
+    // Far field velocity in metres per second
+    pub velocity: Vec2,
+    pub density: Vec3,
+    // Far field temperature, none holds the ambient temperature
+    pub temperature: Option<f32>,
+}
+impl Sponge {
+    pub fn init(thickness: usize, strength: f32) -> Self {
+        Sponge {
+            thickness,
+            strength,
+            velocity: Vec2::ZERO,
+            density: Vec3::ZERO,
+            temperature: None,
+        }
+    }
+}
+
+/// Boundary behavior along one edge of the sim
+pub struct Edge {
+    // Boundary type of cells not covered by any segment
+    pub base: BoundaryType,
+    // Later segments take priority where they overlap
+    pub segments: Vec<Segment>,
+    // Damping layer just inside the edge, if any
+    pub sponge: Option<Sponge>,
+}
+impl Edge {
+    pub fn init(base: BoundaryType) -> Self {
+        Edge {
+            base,
+            segments: Vec::new(),
+            sponge: None,
+        }
+    }
+    /// Gives a run of cells along the edge their own boundary type, such as an inlet
+    /// within a wall
+    pub fn add_segment(&mut self, cells: Range<usize>, boundary: BoundaryType) {
+        self.segments.push(Segment { cells, boundary });
+    }
+    // Splits cells into runs which share a boundary type
+    fn runs(&self, cells: Range<usize>) -> Vec<(Range<usize>, &BoundaryType)> {
+        let owner = |c: usize| self.segments.iter().rposition(|s| s.cells.contains(&c));
+        let boundary =
+            |owner: Option<usize>| owner.map_or(&self.base, |s| &self.segments[s].boundary);
+
+        let mut runs = Vec::new();
+        let mut start = cells.start;
+        for c in cells.start + 1..=cells.end {
+            if c == cells.end || owner(c) != owner(start) {
+                runs.push((start..c, boundary(owner(start))));
+                start = c;
+            }
+        }
+        runs
+    }
+}
+impl From<BoundaryType> for Edge {
+    fn from(base: BoundaryType) -> Self {
+        Edge::init(base)
+    }
+}
+
+/// Edges of the sim
+#[derive(Clone, Copy, Debug)]
+enum Side {
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
 /// Outlines boundary behavior within sim
 pub struct BoundaryParams {
-    pub top: BoundaryType,
-    pub bottom: BoundaryType,
-    pub left: BoundaryType,
-    pub right: BoundaryType,
+    pub top: Edge,
+    pub bottom: Edge,
+    pub left: Edge,
+    pub right: Edge,
+}
+impl BoundaryParams {
+    // Whether the x and y axes wrap around
+    fn periodic(&self) -> [bool; 2] {
+        let wraps = |a: &Edge, b: &Edge| {
+            matches!(a.base, BoundaryType::PERIODIC) && matches!(b.base, BoundaryType::PERIODIC)
+        };
+        [
+            wraps(&self.left, &self.right),
+            wraps(&self.top, &self.bottom),
+        ]
+    }
+    // Panics on a periodic base whose opposite edge isn't periodic, or a periodic segment
+    // along an axis which doesn't wrap, neither has an opposite edge to wrap around to
+    fn validate(&self) {
+        for (side, opposite) in [(Side::Top, Side::Bottom), (Side::Left, Side::Right)] {
+            let periodic =
+                [side, opposite].map(|side| matches!(self.edge(side).base, BoundaryType::PERIODIC));
+            assert!(
+                periodic[0] == periodic[1],
+                "{side:?} and {opposite:?} edges must both have a PERIODIC base or neither"
+            );
+        }
+        let [wrap_x, wrap_y] = self.periodic();
+        for (side, wraps) in [
+            (Side::Top, wrap_y),
+            (Side::Bottom, wrap_y),
+            (Side::Left, wrap_x),
+            (Side::Right, wrap_x),
+        ] {
+            let periodic_segment = self
+                .edge(side)
+                .segments
+                .iter()
+                .any(|s| matches!(s.boundary, BoundaryType::PERIODIC));
+            assert!(
+                wraps || !periodic_segment,
+                "{side:?} edge has a PERIODIC segment but the edges of its axis are not PERIODIC"
+            );
+        }
+    }
+    fn edge(&self, side: Side) -> &Edge {
+        match side {
+            Side::Top => &self.top,
+            Side::Bottom => &self.bottom,
+            Side::Left => &self.left,
+            Side::Right => &self.right,
+        }
+    }
 }
 impl Default for BoundaryParams {
     fn default() -> Self {
         Self {
-            top: BoundaryType::SOLID,
-            bottom: BoundaryType::SOLID,
-            left: BoundaryType::SOLID,
-            right: BoundaryType::SOLID,
+            top: BoundaryType::SOLID(Slip::NoSlip).into(),
+            bottom: BoundaryType::SOLID(Slip::NoSlip).into(),
+            left: BoundaryType::SOLID(Slip::NoSlip).into(),
+            right: BoundaryType::SOLID(Slip::NoSlip).into(),
         }
     }
 }
 
-/// Represents what type of operation is being used on elements
-#[derive(PartialEq, Eq)]
-#[repr(u8)]
-enum Bound {
-    Neither,
-    X,
-    Y,
-}
-
 /// A box which holds a gird of fluid velocity vectors
-pub struct FlowBox {
+///
+/// Fields and time are held at the precision R, f32 by default or f64 for validation runs,
+/// while settings such as FluidParams and obstacles stay f32
+pub struct FlowBox<R: Real = f32> {
     pub dim: (usize, usize),
+    // Width and height of each cell in metres
+    pub spacing: R::Vec2,
+
+    pub vel_x: Vec<R>,
+    vel_x0: Vec<R>,
+    pub vel_y: Vec<R>,
+    vel_y0: Vec<R>,
+
+    pub density: Vec<R::Vec3>,
+    density0: Vec<R::Vec3>,
+
+    pub temperature: Vec<R>,
+    temperature0: Vec<R>,
+
+    // Pressure divided by density in m²/s², as left by the last projection
+    pub pressure: Vec<R>,
+    // Pressure left by the first projection of the last step, whose openings are not held
+    // so it solves for a different pressure, kept as its first guess
+    pressure0: Vec<R>,
 
-    pub vel_x: Vec<f32>,
-    vel_x0: Vec<f32>,
-    pub vel_y: Vec<f32>,
-    vel_y0: Vec<f32>,
+    edges: Vec<EdgeKind>,
 
-    pub density: Vec<Vec3>,
-    density0: Vec<Vec3>,
+    fixed_solid: Vec<bool>,
+    solid: Vec<bool>,
+    solid_vel_x: Vec<R>,
+    solid_vel_y: Vec<R>,
+    // Friction of the surface covering each solid cell, 0 slides freely and 1 sticks
+    solid_friction: Vec<f32>,
+    pub obstacles: Vec<Obstacle>,
+
+    // Seconds simulated so far
+    time: R,
 
     fluid_params: FluidParams,
     boundary_params: BoundaryParams,
 }
-impl FlowBox {
+impl<R: Real> FlowBox<R> {
     /* Initializing */
     pub fn init(width: usize, height: usize) -> Self {
-        FlowBox::init_with_params(
+        Self::init_with_params(
             width,
             height,
             FluidParams::default(),
             BoundaryParams::default(),
         )
     }
+    /// Panics if a PERIODIC base faces an edge which isn't PERIODIC, or a PERIODIC segment
+    /// lies along an edge whose axis doesn't wrap
     pub fn init_with_params(
         width: usize,
         height: usize,
         fluid_params: FluidParams,
         boundary_params: BoundaryParams,
     ) -> Self {
+        boundary_params.validate();
+        let ambient = R::from_f32(fluid_params.ambient_temperature);
         FlowBox {
             dim: (width, height),
-            vel_x: vec![0.0; width * height],
-            vel_x0: vec![0.0; width * height],
-            vel_y: vec![0.0; width * height],
-            vel_y0: vec![0.0; width * height],
-            density: vec![Vec3::ZERO; width * height],
-            density0: vec![Vec3::ZERO; width * height],
+            spacing: R::vec2(Vec2::splat(DEFAULT_CELL_SIZE)),
+            vel_x: vec![R::zero(); width * height],
+            vel_x0: vec![R::zero(); width * height],
+            vel_y: vec![R::zero(); width * height],
+            vel_y0: vec![R::zero(); width * height],
+            density: vec![R::Vec3::splat(R::zero()); width * height],
+            density0: vec![R::Vec3::splat(R::zero()); width * height],
+            temperature: vec![ambient; width * height],
+            temperature0: vec![ambient; width * height],
+            pressure: vec![R::zero(); width * height],
+            pressure0: vec![R::zero(); width * height],
+            edges: Self::edge_kinds(&(width, height), &boundary_params),
+            fixed_solid: vec![false; width * height],
+            solid: vec![false; width * height],
+            solid_vel_x: vec![R::zero(); width * height],
+            solid_vel_y: vec![R::zero(); width * height],
+            solid_friction: vec![1.0; width * height],
+            obstacles: Vec::new(),
+            time: R::zero(),
             fluid_params,
             boundary_params,
         }
     }
 
+    /// Sets the width and height of the whole grid in metres
+    pub fn set_size(&mut self, width: R, height: R) {
+        self.spacing = [
+            width / R::from_usize(self.dim.0),
+            height / R::from_usize(self.dim.1),
+        ]
+        .into();
+    }
+    /// Returns the width and height of the whole grid in metres
+    pub fn size(&self) -> R::Vec2 {
+        let [dx, dy]: [R; 2] = self.spacing.into();
+        [
+            dx * R::from_usize(self.dim.0),
+            dy * R::from_usize(self.dim.1),
+        ]
+        .into()
+    }
+
+    /// Returns the seconds simulated so far
+    pub fn time(&self) -> R {
+        self.time
+    }
+
     /* Interacting with Fluids */
     pub fn add_fluid_density(&mut self, x: usize, y: usize, color: [f32; 4]) {
-        let i = Self::index(
+        let i = index(
             &x.clamp(0, self.dim.0 - 1),
             &y.clamp(0, self.dim.1 - 1),
             &self.dim,
         );
-        self.density[i] = self.density[i].add(Vec3::new(color[0], color[1], color[2]));
+        self.density[i] += R::vec3(Vec3::new(color[0], color[1], color[2]));
     }
-    pub fn add_fluid_velocity(&mut self, x: usize, y: usize, vx: f32, vy: f32) {
-        let i = Self::index(
+    /// Adds velocity in metres per second to a cell
+    pub fn add_fluid_velocity(&mut self, x: usize, y: usize, vx: R, vy: R) {
+        let i = index(
             &x.clamp(0, self.dim.0 - 1),
             &y.clamp(0, self.dim.1 - 1),
             &self.dim,
         );
-        self.vel_x0[i] += vx;
-        self.vel_y0[i] += vy;
+        self.vel_x[i] += vx;
+        self.vel_y[i] += vy;
     }
-    pub fn add_fluid_velocity_angle_mag(&mut self, x: usize, y: usize, angle: f32, mag: f32) {
+    pub fn add_fluid_velocity_angle_mag(&mut self, x: usize, y: usize, angle: R, mag: R) {
         self.add_fluid_velocity(x, y, angle.cos() * mag, angle.sin() * mag);
     }
-    pub fn scale_fluid_density(&mut self, mag: f32) {
+    pub fn scale_fluid_density(&mut self, mag: R) {
         self.density.par_iter_mut().for_each(|d| *d *= mag);
     }
+    /// Adds heat to a cell, negative amounts cool it
+    pub fn add_heat(&mut self, x: usize, y: usize, amount: R) {
+        let i = index(
+            &x.clamp(0, self.dim.0 - 1),
+            &y.clamp(0, self.dim.1 - 1),
+            &self.dim,
+        );
+        self.temperature[i] += amount;
+    }
+    /// Sets the temperature of a cell, calling this every step holds it fixed like a heated wall
+    pub fn set_temperature(&mut self, x: usize, y: usize, temperature: R) {
+        let i = index(
+            &x.clamp(0, self.dim.0 - 1),
+            &y.clamp(0, self.dim.1 - 1),
+            &self.dim,
+        );
+        self.temperature[i] = temperature;
+    }
 
-    pub fn step(&mut self, dt: f32) {
-        self.apply_boundary_conditions(dt);
+    /* Obstacles */
+    /// Marks a cell as solid or fluid, cells on the outer walls are ignored
+    pub fn set_solid(&mut self, x: usize, y: usize, solid: bool) {
+        if !(1..self.dim.0 - 1).contains(&x) || !(1..self.dim.1 - 1).contains(&y) {
+            return;
+        }
+        let i = index(&x, &y, &self.dim);
+        self.fixed_solid[i] = solid;
+        self.solid[i] = solid;
+        if solid {
+            let ambient = R::from_f32(self.fluid_params.ambient_temperature);
+            self.vel_x[i] = R::zero();
+            self.vel_x0[i] = R::zero();
+            self.vel_y[i] = R::zero();
+            self.vel_y0[i] = R::zero();
+            self.density[i] = R::Vec3::splat(R::zero());
+            self.density0[i] = R::Vec3::splat(R::zero());
+            self.temperature[i] = ambient;
+            self.temperature0[i] = ambient;
+        }
+    }
+    /// Returns the largest divergence in any fluid cell per second measured by central
+    /// differences over two cells, solid neighbors count as the flow mirrored about their
+    /// surface
+    pub fn max_divergence(&self) -> R {
+        max_divergence(
+            &Grid::new(self.dim.into()),
+            self.spacing.into(),
+            &self.solid,
+            [&self.solid_vel_x, &self.solid_vel_y],
+            [&self.vel_x, &self.vel_y],
+        )
+    }
+    /// Returns whether the cell at x, y is solid
+    pub fn is_solid(&self, x: usize, y: usize) -> bool {
+        x < self.dim.0 && y < self.dim.1 && self.solid[index(&x, &y, &self.dim)]
+    }
+    /// Turns every fixed solid cell back into fluid, moving obstacles are kept
+    pub fn clear_solids(&mut self) {
+        self.fixed_solid.fill(false);
+        self.solid.fill(false);
+    }
+    /// Fills a rectangle of cells starting at x, y with solid
+    pub fn add_solid_rect(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        for cy in y..y + height {
+            for cx in x..x + width {
+                self.set_solid(cx, cy, true);
+            }
+        }
+    }
+    /// Fills every cell whose center lies within the circle with solid
+    pub fn add_solid_circle(&mut self, cx: f32, cy: f32, radius: f32) {
+        self.add_solid_where(|x, y| (x - cx).powi(2) + (y - cy).powi(2) <= radius * radius);
+    }
+    /// Fills every cell whose center lies within the polygon with solid
+    pub fn add_solid_polygon(&mut self, points: &[(f32, f32)]) {
+        let points: Vec<Vec2> = points.iter().map(|&p| p.into()).collect();
+        self.add_solid_where(|x, y| polygon_contains(&points, Vec2::new(x, y)));
+    }
+    // Marks every interior cell whose center passes the test as solid
+    fn add_solid_where<F: Fn(f32, f32) -> bool>(&mut self, inside: F) {
+        for y in 1..self.dim.1 - 1 {
+            for x in 1..self.dim.0 - 1 {
+                if inside(x as f32, y as f32) {
+                    self.set_solid(x, y, true);
+                }
+            }
+        }
+    }
+    /// Adds a moving obstacle to the sim returning its index within obstacles
+    pub fn add_obstacle(&mut self, obstacle: Obstacle) -> usize {
+        self.obstacles.push(obstacle);
+        self.obstacles.len() - 1
+    }
+    // Moves obstacles forward and rebuilds the solid cells they cover
+    fn move_obstacles(&mut self, dt: R) {
+        let [dx, dy]: [R; 2] = self.spacing.into();
+        let spacing = Vec2::new(dx.as_f32(), dy.as_f32());
+        self.obstacles
+            .iter_mut()
+            .for_each(|o| o.advance(dt.as_f32(), spacing));
 
-        Self::diffuse(
-            &Bound::X,
-            &mut self.vel_x0,
-            &self.vel_x,
-            self.fluid_params.viscosity,
+        let dim = self.dim;
+        let obstacles = &self.obstacles;
+        let fixed_solid = &self.fixed_solid;
+        let fixed_friction = self.fluid_params.solid_slip.friction();
+        let covered: Vec<Option<(R::Vec2, f32)>> = (0..dim.0 * dim.1)
+            .into_par_iter()
+            .map(|i| {
+                let (x, y) = pos(&i, &dim);
+                if fixed_solid[i] {
+                    return Some(([R::zero(); 2].into(), fixed_friction));
+                }
+                if !(1..dim.0 - 1).contains(&x) || !(1..dim.1 - 1).contains(&y) {
+                    return None;
+                }
+                let p = Vec2::new(x as f32, y as f32);
+                obstacles
+                    .iter()
+                    .find(|o| o.contains(p))
+                    .map(|o| (R::vec2(o.surface_velocity(p, spacing)), o.slip.friction()))
+            })
+            .collect();
+
+        for (i, cell) in covered.into_iter().enumerate() {
+            match cell {
+                Some((vel, friction)) => {
+                    let [vx, vy]: [R; 2] = vel.into();
+                    self.solid[i] = true;
+                    self.solid_vel_x[i] = vx;
+                    self.solid_vel_y[i] = vy;
+                    self.solid_friction[i] = friction;
+                }
+                None if self.solid[i] => {
+                    // Cells left behind by an obstacle take on its velocity, their other
+                    // values were already mirrored in from the surrounding fluid
+                    self.solid[i] = false;
+                    self.vel_x[i] = self.solid_vel_x[i];
+                    self.vel_x0[i] = self.solid_vel_x[i];
+                    self.vel_y[i] = self.solid_vel_y[i];
+                    self.vel_y0[i] = self.solid_vel_y[i];
+                    self.solid_vel_x[i] = R::zero();
+                    self.solid_vel_y[i] = R::zero();
+                }
+                None => (),
+            }
+        }
+    }
+
+    pub fn step(&mut self, dt: R) -> StepReport {
+        let mut report = StepReport::default();
+
+        self.move_obstacles(dt);
+        apply_body_forces(
+            [&mut self.vel_x, &mut self.vel_y],
+            &self.density,
+            &self.temperature,
+            &self.solid,
+            &self.fluid_params,
             dt,
-            self.fluid_params.diffuse_iters,
-            &self.dim,
         );
-        Self::diffuse(
-            &Bound::Y,
-            &mut self.vel_y0,
-            &self.vel_y,
-            self.fluid_params.viscosity,
+        confine_vorticity(
+            &Grid::new(self.dim.into()),
+            self.spacing.into(),
+            &self.solid,
+            [&mut self.vel_x, &mut self.vel_y],
+            self.fluid_params.vorticity_confinement,
             dt,
-            self.fluid_params.diffuse_iters,
-            &self.dim,
         );
+        self.apply_sponges(dt);
+        self.apply_boundary_conditions();
+        self.convect_outflows(dt);
+
+        let domain = Domain {
+            grid: Grid::new(self.dim.into()),
+            spacing: self.spacing.into(),
+            periodic: self.boundary_params.periodic(),
+            edges: &self.edges,
+            hold_pressure: false,
+            solid: &self.solid,
+            solid_vel: [&self.solid_vel_x, &self.solid_vel_y],
+            solid_friction: &self.solid_friction,
+        };
+        let params = &self.fluid_params;
 
-        Self::project(
+        report.add_diffuse(domain.diffuse(
+            &Bound::Velocity(0),
             &mut self.vel_x0,
+            &self.vel_x,
+            params.viscosity,
+            dt,
+            params,
+        ));
+        report.add_diffuse(domain.diffuse(
+            &Bound::Velocity(1),
             &mut self.vel_y0,
+            &self.vel_y,
+            params.viscosity,
+            dt,
+            params,
+        ));
+
+        let solve = domain.project(
+            [&mut self.vel_x0, &mut self.vel_y0],
+            &mut self.pressure0,
             &mut self.vel_x,
-            &mut self.vel_y,
-            self.fluid_params.project_iters,
-            &self.dim,
+            dt,
+            params,
         );
+        report.add_pressure(solve);
 
-        Self::advect(
-            &Bound::X,
+        let vel0 = [self.vel_x0.as_slice(), self.vel_y0.as_slice()];
+        domain.advect(
+            &Bound::Velocity(0),
             &mut self.vel_x,
             &self.vel_x0,
-            &self.vel_x0,
-            &self.vel_y0,
+            vel0,
             dt,
-            &self.dim,
+            params,
         );
-        Self::advect(
-            &Bound::Y,
+        domain.advect(
+            &Bound::Velocity(1),
             &mut self.vel_y,
             &self.vel_y0,
-            &self.vel_x0,
-            &self.vel_y0,
+            vel0,
             dt,
-            &self.dim,
+            params,
         );
-        Self::project(
-            &mut self.vel_x,
-            &mut self.vel_y,
+        let solve = Domain {
+            hold_pressure: true,
+            ..domain
+        }
+        .project(
+            [&mut self.vel_x, &mut self.vel_y],
+            &mut self.pressure,
             &mut self.vel_x0,
-            &mut self.vel_y0,
-            self.fluid_params.project_iters,
-            &self.dim,
+            dt,
+            params,
         );
+        report.add_pressure(solve);
+        report.max_divergence = self.max_divergence().as_f32();
 
-        Self::diffuse(
+        let vel = [self.vel_x.as_slice(), self.vel_y.as_slice()];
+        report.add_diffuse(domain.diffuse(
             &Bound::Neither,
             &mut self.density0,
             &self.density,
-            self.fluid_params.diffusion_rate,
+            params.diffusion_rate,
             dt,
-            self.fluid_params.diffuse_iters,
-            &self.dim,
-        );
-        Self::advect(
+            params,
+        ));
+        domain.advect(
             &Bound::Neither,
             &mut self.density,
             &self.density0,
-            &self.vel_x,
-            &self.vel_y,
+            vel,
             dt,
-            &self.dim,
+            params,
+        );
+
+        report.add_diffuse(domain.diffuse(
+            &Bound::Neither,
+            &mut self.temperature0,
+            &self.temperature,
+            params.conductivity,
+            dt,
+            params,
+        ));
+        domain.advect(
+            &Bound::Neither,
+            &mut self.temperature,
+            &self.temperature0,
+            vel,
+            dt,
+            params,
         );
+        self.time += dt;
+        report
     }
-    fn apply_boundary_conditions(&mut self, dt: f32) {
-        match self.boundary_params.top {
-            BoundaryType::INLET(speed, add_density) => {
-                for x in 1..self.dim.0 - 1 {
-                    self.vel_y[Self::index(&x, &1, &self.dim)] = speed * dt;
-                }
-                if add_density {
-                    self.add_fluid_density(self.dim.0 / 2, 1, [1.0, 0.0, 0.0, 0.0])
-                }
-            }
-            BoundaryType::OUTLET => {
-                for x in 1..self.dim.0 - 1 {
-                    self.vel_y[Self::index(&x, &1, &self.dim)] = 0.0;
-                }
+    /// Steps forward by frame_dt in as many substeps as are needed to keep the CFL number
+    /// within max_cfl, returning the reports of every substep merged and how many were taken
+    pub fn step_adaptive(&mut self, frame_dt: R) -> (StepReport, usize) {
+        let (max_cfl, max_substeps) = (self.fluid_params.max_cfl, self.fluid_params.max_substeps);
+        step_adaptive(self, frame_dt, max_cfl, max_substeps, Self::cfl, Self::step)
+    }
+    /// Returns the largest number of cells fluid would cross in a step of dt
+    pub fn cfl(&self, dt: R) -> R {
+        cfl([&self.vel_x, &self.vel_y], self.spacing.into(), dt)
+    }
+    // Relaxes cells within each sponge layer towards its far field state, more strongly the
+    // closer they are to the edge
+    fn apply_sponges(&mut self, dt: R) {
+        let dim = self.dim;
+        let ambient = self.fluid_params.ambient_temperature;
+        for side in [Side::Top, Side::Bottom, Side::Left, Side::Right] {
+            let Some(sponge) = &self.boundary_params.edge(side).sponge else {
+                continue;
+            };
+            if sponge.thickness == 0 {
+                continue;
             }
-            BoundaryType::SOLID => (),
-        }
+            let far_velocity: [R; 2] = R::vec2(sponge.velocity).into();
+            let far_density = R::vec3(sponge.density);
+            let far_temperature = R::from_f32(sponge.temperature.unwrap_or(ambient));
+            let strength = R::from_f32(sponge.strength);
+            let thickness = sponge.thickness;
 
-        match self.boundary_params.bottom {
-            BoundaryType::INLET(speed, add_density) => {
-                for x in 1..self.dim.0 - 1 {
-                    self.vel_y[Self::index(&x, &(self.dim.1 - 2), &self.dim)] = -speed * dt;
-                }
-                if add_density {
-                    self.add_fluid_density(self.dim.0 / 2, self.dim.1 - 2, [1.0, 0.0, 0.0, 0.0])
-                }
-            }
-            BoundaryType::OUTLET => {
-                for x in 1..self.dim.0 - 1 {
-                    self.vel_y[Self::index(&x, &(self.dim.1 - 2), &self.dim)] = 0.0;
-                }
+            // Interior rows and columns of cells from the edge inwards to depth thickness, the
+            // ramp reaches nothing one cell past the last
+            let inner = |n: usize| (1 + thickness).min(n - 1);
+            let outer = |n: usize| n.saturating_sub(1 + thickness).max(1)..n - 1;
+            let (rows, cols) = match side {
+                Side::Top => (1..inner(dim.1), 1..dim.0 - 1),
+                Side::Bottom => (outer(dim.1), 1..dim.0 - 1),
+                Side::Left => (1..dim.1 - 1, 1..inner(dim.0)),
+                Side::Right => (1..dim.1 - 1, outer(dim.0)),
+            };
+            self.vel_x
+                .par_chunks_mut(dim.0)
+                .zip(self.vel_y.par_chunks_mut(dim.0))
+                .zip(self.density.par_chunks_mut(dim.0))
+                .zip(self.temperature.par_chunks_mut(dim.0))
+                .zip(self.solid.par_chunks(dim.0))
+                .enumerate()
+                .skip(rows.start)
+                .take(rows.len())
+                .for_each(|(y, ((((vel_x, vel_y), density), temperature), solid))| {
+                    for x in cols.clone() {
+                        if solid[x] {
+                            continue;
+                        }
+                        let depth = match side {
+                            Side::Top => y,
+                            Side::Bottom => dim.1 - 1 - y,
+                            Side::Left => x,
+                            Side::Right => dim.0 - 1 - x,
+                        };
+                        let ramp = R::from_usize(thickness + 1 - depth) / R::from_usize(thickness);
+                        let blend = R::one() - (-strength * ramp * ramp * dt).exp();
+
+                        vel_x[x] = vel_x[x] + (far_velocity[0] - vel_x[x]) * blend;
+                        vel_y[x] = vel_y[x] + (far_velocity[1] - vel_y[x]) * blend;
+                        density[x] = density[x] + (far_density - density[x]) * blend;
+                        temperature[x] =
+                            temperature[x] + (far_temperature - temperature[x]) * blend;
+                    }
+                });
+        }
+    }
+    // Carries values out through convective outflows, each edge cell moves towards the cell
+    // inside it as fast as the flow leaving through it so nothing is reflected back in
+    fn convect_outflows(&mut self, dt: R) {
+        let dim = self.dim;
+        let [wrap_x, wrap_y] = self.boundary_params.periodic();
+        let [dx, dy]: [R; 2] = self.spacing.into();
+        for ghost in 0..dim.0 * dim.1 {
+            if self.edges[ghost] != EdgeKind::ConvectiveOutflow {
+                continue;
             }
-            BoundaryType::SOLID => (),
+            let (x, y) = pos(&ghost, &dim);
+            let (inner, outward, spacing) = if x == 0 && !wrap_x {
+                let inner = index(&1, &y, &dim);
+                (inner, -self.vel_x[inner], dx)
+            } else if x == dim.0 - 1 && !wrap_x {
+                let inner = index(&(x - 1), &y, &dim);
+                (inner, self.vel_x[inner], dx)
+            } else if y == 0 && !wrap_y {
+                let inner = index(&x, &1, &dim);
+                (inner, -self.vel_y[inner], dy)
+            } else if y == dim.1 - 1 && !wrap_y {
+                let inner = index(&x, &(y - 1), &dim);
+                (inner, self.vel_y[inner], dy)
+            } else {
+                continue;
+            };
+            let c = (outward * dt / spacing).clamp(R::zero(), R::one());
+
+            self.vel_x[ghost] = self.vel_x[ghost] + (self.vel_x[inner] - self.vel_x[ghost]) * c;
+            self.vel_y[ghost] = self.vel_y[ghost] + (self.vel_y[inner] - self.vel_y[ghost]) * c;
+            self.density[ghost] =
+                self.density[ghost] + (self.density[inner] - self.density[ghost]) * c;
+            self.temperature[ghost] =
+                self.temperature[ghost] + (self.temperature[inner] - self.temperature[ghost]) * c;
         }
+    }
+    fn apply_boundary_conditions(&mut self) {
+        let dim = self.dim;
+        let [wrap_x, wrap_y] = self.boundary_params.periodic();
 
-        match self.boundary_params.left {
-            BoundaryType::INLET(speed, add_density) => {
-                for y in 1..self.dim.1 - 1 {
-                    self.vel_x[Self::index(&1, &y, &self.dim)] = speed * dt;
-                }
-                if add_density {
-                    self.add_fluid_density(1, self.dim.1 / 2, [1.0, 0.0, 0.0, 0.0])
-                }
-            }
-            BoundaryType::OUTLET => {
-                for y in 1..self.dim.1 - 1 {
-                    self.vel_x[Self::index(&1, &y, &self.dim)] = 0.0;
+        for side in [Side::Top, Side::Bottom, Side::Left, Side::Right] {
+            // Cells along the edge, the sign of velocity pointing into the sim and the
+            // velocity normal to the edge
+            let (len, inward, normal) = match side {
+                Side::Top if !wrap_y => (dim.0, 1.0, &mut self.vel_y),
+                Side::Bottom if !wrap_y => (dim.0, -1.0, &mut self.vel_y),
+                Side::Left if !wrap_x => (dim.1, 1.0, &mut self.vel_x),
+                Side::Right if !wrap_x => (dim.1, -1.0, &mut self.vel_x),
+                _ => continue,
+            };
+
+            for (cells, boundary) in self.boundary_params.edge(side).runs(1..len - 1) {
+                match boundary {
+                    BoundaryType::INLET(inlet) => {
+                        let width = cells.len() as f32;
+                        for c in cells.clone() {
+                            let i = Self::edge_cell(side, c, &dim);
+                            let s = (c - cells.start) as f32 / width + 0.5 / width;
+                            normal[i] = R::from_f32(
+                                inward * inlet.speed * inlet.profile.factor(s, self.time.as_f32()),
+                            );
+                            if let Some(density) = inlet.density {
+                                self.density[i] = R::vec3(density);
+                            }
+                            if let Some(temperature) = inlet.temperature {
+                                self.temperature[i] = R::from_f32(temperature);
+                            }
+                        }
+                    }
+                    BoundaryType::OUTLET
+                    | BoundaryType::CONVECTIVE_OUTLET
+                    | BoundaryType::PRESSURE(_)
+                    | BoundaryType::SOLID(_)
+                    | BoundaryType::MOVING_WALL(_)
+                    | BoundaryType::PERIODIC => (),
                 }
             }
-            BoundaryType::SOLID => (),
         }
 
-        match self.boundary_params.right {
-            BoundaryType::INLET(speed, add_density) => {
-                for y in 1..self.dim.1 - 1 {
-                    self.vel_x[Self::index(&(self.dim.0 - 2), &y, &self.dim)] = -speed * dt;
-                }
-                if add_density {
-                    self.add_fluid_density(self.dim.0 - 2, self.dim.1 / 2, [1.0, 0.0, 0.0, 0.0])
-                }
-            }
-            BoundaryType::OUTLET => {
-                for y in 1..self.dim.1 - 1 {
-                    self.vel_x[Self::index(&(self.dim.0 - 2), &y, &self.dim)] = 0.0;
+        let domain = Domain {
+            grid: Grid::new(self.dim.into()),
+            spacing: self.spacing.into(),
+            periodic: self.boundary_params.periodic(),
+            edges: &self.edges,
+            hold_pressure: false,
+            solid: &self.solid,
+            solid_vel: [&self.solid_vel_x, &self.solid_vel_y],
+            solid_friction: &self.solid_friction,
+        };
+        domain.set_bound(&Bound::Velocity(0), &mut self.vel_x);
+        domain.set_bound(&Bound::Velocity(1), &mut self.vel_y);
+    }
+    // Works out how each edge cell is filled
+    fn edge_kinds(dim: &(usize, usize), boundary_params: &BoundaryParams) -> Vec<EdgeKind> {
+        let mut edges = vec![EdgeKind::Wall(1.0); dim.0 * dim.1];
+        for side in [Side::Top, Side::Bottom, Side::Left, Side::Right] {
+            let len = match side {
+                Side::Top | Side::Bottom => dim.0,
+                Side::Left | Side::Right => dim.1,
+            };
+            for (cells, boundary) in boundary_params.edge(side).runs(1..len - 1) {
+                let kind = match boundary {
+                    BoundaryType::INLET(_) => EdgeKind::Inlet,
+                    BoundaryType::OUTLET => EdgeKind::Outflow,
+                    BoundaryType::CONVECTIVE_OUTLET => EdgeKind::ConvectiveOutflow,
+                    BoundaryType::SOLID(slip) => EdgeKind::Wall(slip.friction()),
+                    BoundaryType::PRESSURE(value) => EdgeKind::FixedPressure(*value),
+                    BoundaryType::MOVING_WALL(speed) => EdgeKind::MovingWall(*speed),
+                    // Only found along axes which wrap, where edge kinds go unused
+                    BoundaryType::PERIODIC => EdgeKind::Wall(1.0),
+                };
+                for c in cells {
+                    let ghost = match side {
+                        Side::Top => index(&c, &0, dim),
+                        Side::Bottom => index(&c, &(dim.1 - 1), dim),
+                        Side::Left => index(&0, &c, dim),
+                        Side::Right => index(&(dim.0 - 1), &c, dim),
+                    };
+                    edges[ghost] = kind;
                 }
             }
-            BoundaryType::SOLID => (),
         }
-
-        Self::set_bound(&Bound::X, &mut self.vel_x, &self.dim);
-        Self::set_bound(&Bound::Y, &mut self.vel_y, &self.dim);
+        edges
     }
-    // Handles boundary conditions of the sim
-    fn set_bound<T>(b: &Bound, vals: &mut [T], dim: &(usize, usize))
-    where
-        T: Copy
-            + Add<Output = T>
-            + Sub<Output = T>
-            + Mul<Output = T>
-            + Mul<f32, Output = T>
-            + Div<Output = T>
-            + Send
-            + Sync,
-    {
-        // Deals with the top and bottom boundaries
-        let vals_clone = vals.to_vec();
-        let dir = if b == &Bound::X { -1.0 } else { 1.0 };
-
-        for x in 1..dim.0 - 1 {
-            vals[Self::index(&x, &0, dim)] = vals_clone[Self::index(&x, &1, dim)].mul(dir);
-            vals[Self::index(&x, &(dim.1 - 1), dim)] =
-                vals_clone[Self::index(&x, &(dim.1 - 2), dim)].mul(dir);
-        }
-
-        // Deals with the side boundaries
-        let dir = if b == &Bound::Y { -1.0 } else { 1.0 };
-        for y in 1..dim.1 - 1 {
-            vals[Self::index(&0, &y, dim)] = vals_clone[Self::index(&1, &y, dim)].mul(dir);
-            vals[Self::index(&(dim.0 - 1), &y, dim)] =
-                vals_clone[Self::index(&(dim.0 - 2), &y, dim)].mul(dir);
-        }
-
-        vals[Self::index(&0, &0, dim)] =
-            (vals[Self::index(&1, &0, dim)] + vals[Self::index(&0, &1, dim)]).mul(0.5);
-
-        vals[Self::index(&0, &(dim.1 - 1), dim)] = (vals[Self::index(&1, &(dim.1 - 1), dim)]
-            + vals[Self::index(&0, &(dim.1 - 2), dim)])
-        .mul(0.5);
-
-        vals[Self::index(&(dim.0 - 1), &0, dim)] = (vals[Self::index(&(dim.0 - 2), &0, dim)]
-            + vals[Self::index(&(dim.0 - 1), &1, dim)])
-        .mul(0.5);
-
-        vals[Self::index(&(dim.0 - 1), &(dim.1 - 1), dim)] = (vals
-            [Self::index(&(dim.0 - 2), &(dim.1 - 1), dim)]
-            + vals[Self::index(&(dim.0 - 1), &(dim.1 - 2), dim)])
-        .mul(0.5);
-    }
-    /// Linear solver Gauss Seidel method
-    fn lin_solve<T>(
-        bound: &Bound,
-        vals: &mut [T],
-        vals0: &[T],
-        a: f32,
-        c: f32,
-        iters: usize,
-        dim: &(usize, usize),
-    ) where
-        T: Copy
-            + Add<Output = T>
-            + Sub<Output = T>
-            + Mul<Output = T>
-            + Mul<f32, Output = T>
-            + Div<Output = T>
-            + Send
-            + Sync,
-    {
-        let c_recip = c.recip();
-
-        for _ in 0..iters {
-            let clone_vals = vals.to_vec();
-
-            vals.par_iter_mut().enumerate().for_each(|(i, v)| {
-                let (x, y) = Self::pos(&i, dim);
-                if (1..dim.0 - 1).contains(&x) && (1..dim.1 - 1).contains(&y) {
-                    *v = (vals0[i]
-                        + (clone_vals[Self::index(&(x + 1), &y, dim)]
-                            + clone_vals[Self::index(&(x - 1), &y, dim)]
-                            + clone_vals[Self::index(&x, &(y + 1), dim)]
-                            + clone_vals[Self::index(&x, &(y - 1), dim)])
-                        .mul(a))
-                        * c_recip;
-                }
-            });
-            Self::set_bound(bound, vals, dim);
-        }
-    }
-    /// Diffuses out values over a larger area
-    fn diffuse<T>(
-        b: &Bound,
-        vals: &mut [T],
-        vals0: &[T],
-        diff: f32,
-        dt: f32,
-        iters: usize,
-        dim: &(usize, usize),
-    ) where
-        T: Copy
-            + Add<Output = T>
-            + Sub<Output = T>
-            + Mul<Output = T>
-            + Mul<f32, Output = T>
-            + Div<Output = T>
-            + Send
-            + Sync,
-    {
-        let a = dt * diff * 10000.0;
-        Self::lin_solve(b, vals, vals0, a, 1.0 + 4.0 * a, iters, dim);
-    }
-    /// Solves for divergence
-    fn project(
-        vel_x: &mut [f32],
-        vel_y: &mut [f32],
-        p: &mut [f32],
-        div: &mut [f32],
-        iters: usize,
-        dim: &(usize, usize),
-    ) {
-        div.par_iter_mut()
-            .zip(p.par_iter_mut())
-            .enumerate()
-            .for_each(|(i, (v, pv))| {
-                let (x, y) = Self::pos(&i, dim);
-
-                if (1..dim.0 - 1).contains(&x) && (1..dim.1 - 1).contains(&y) {
-                    *v = -0.5
-                        * (vel_x[Self::index(&(x + 1), &&y, dim)]
-                            - vel_x[Self::index(&(x - 1), &y, dim)]
-                            + vel_y[Self::index(&x, &(y + 1), dim)]
-                            - vel_y[Self::index(&x, &(y - 1), dim)]);
-                }
-
-                *pv = 0.0;
-            });
-
-        Self::set_bound(&Bound::Neither, div, dim);
-        Self::set_bound(&Bound::Neither, p, dim);
-        Self::lin_solve(&Bound::Neither, p, div, 1.0, 6.0, iters, dim);
-
-        vel_x
-            .par_iter_mut()
-            .zip(vel_y.par_iter_mut())
-            .enumerate()
-            .for_each(|(i, (vx, vy))| {
-                let (x, y) = Self::pos(&i, dim);
-
-                if (1..dim.0 - 1).contains(&x) && (1..dim.1 - 1).contains(&y) {
-                    *vx -= 0.5
-                        * (p[Self::index(&(x + 1), &y, dim)] - p[Self::index(&(x - 1), &y, dim)]);
-                    *vy -= 0.5
-                        * (p[Self::index(&x, &(y + 1), dim)] - p[Self::index(&x, &(y - 1), dim)]);
-                }
-            });
-
-        Self::set_bound(&Bound::X, vel_x, dim);
-        Self::set_bound(&Bound::Y, vel_y, dim);
-    }
-    // Moves values along fluids direction of travel
-    fn advect<T>(
-        bound: &Bound,
-        vals: &mut [T],
-        vals0: &[T],
-        vel_x: &[f32],
-        vel_y: &[f32],
-        dt: f32,
-        dim: &(usize, usize),
-    ) where
-        T: Copy
-            + Add<Output = T>
-            + Sub<Output = T>
-            + Mul<Output = T>
-            + Mul<f32, Output = T>
-            + Div<Output = T>
-            + Send
-            + Sync,
-    {
-        let dtx = dt * 100.0;
-        let dty = dt * 100.0;
-
-        let l = vals0.len() - 1;
-
-        vals.par_iter_mut().enumerate().for_each(|(ix, v)| {
-            let (i, j) = Self::pos(&ix, dim);
+    // Index of the cell just inside an edge, some distance along it
+    fn edge_cell(side: Side, along: usize, dim: &(usize, usize)) -> usize {
+        match side {
+            Side::Top => index(&along, &1, dim),
+            Side::Bottom => index(&along, &(dim.1 - 2), dim),
+            Side::Left => index(&1, &along, dim),
+            Side::Right => index(&(dim.0 - 2), &along, dim),
+        }
+    }
+}
+impl FlowBox {
+    /// Returns index value for the x, y position, same as the free index
+    #[inline]
+    pub fn index(x: &usize, y: &usize, dim: &(usize, usize)) -> usize {
+        index(x, y, dim)
+    }
+    /// Returns the x, y position given the index, same as the free pos
+    #[inline]
+    pub fn pos(i: &usize, dim: &(usize, usize)) -> (usize, usize) {
+        pos(i, dim)
+    }
+}
 
-            let j_float = j as f32;
-            let i_float = i as f32;
+/// Returns index value for the x, y position
+#[inline]
+pub fn index(x: &usize, y: &usize, dim: &(usize, usize)) -> usize {
+    x + y * dim.0
+}
+/// Returns the x, y position given the index
+#[inline]
+pub fn pos(i: &usize, dim: &(usize, usize)) -> (usize, usize) {
+    (i % dim.0, i / dim.0)
+}
 
-            let tmp1 = dtx * vel_x[ix];
-            let tmp2 = dty * vel_y[ix];
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pressure::{Cycle, Preconditioner};
 
-            let mut x = i_float - tmp1;
-            let mut y = j_float - tmp2;
+    const SOLVERS: [PressureSolver; 3] = [
+        PressureSolver::ConjugateGradient(Preconditioner::Jacobi),
+        PressureSolver::ConjugateGradient(Preconditioner::IncompleteCholesky),
+        PressureSolver::Multigrid(Cycle::V),
+    ];
 
-            x = x.clamp(0.5, dim.0 as f32 - 0.5);
+    fn params(pressure_solver: PressureSolver) -> FluidParams {
+        FluidParams {
+            pressure_solver,
+            pressure_tolerance: 1e-6,
+            ..FluidParams::default()
+        }
+    }
 
-            let i0 = x.floor();
-            let i1 = i0 + 1.0;
+    // A channel from an inlet on the left to an outlet on the right around a solid block
+    fn channel() -> BoundaryParams {
+        BoundaryParams {
+            left: BoundaryType::INLET(Inlet::init(1.0)).into(),
+            right: BoundaryType::OUTLET.into(),
+            ..BoundaryParams::default()
+        }
+    }
 
-            y = y.clamp(0.5, dim.1 as f32 - 0.5);
+    // Stirs the fluid so each projection has divergence to remove
+    fn stir(flow_box: &mut FlowBox, step: usize) {
+        for k in 0..10 {
+            flow_box.add_fluid_velocity(5 + k * 3, 5 + (k * 7 + step) % 20, 1.0, -0.5);
+        }
+    }
 
-            let j0 = y.floor();
-            let j1 = j0 + 1.0;
+    #[test]
+    fn projection_leaves_no_divergence() {
+        for solver in SOLVERS {
+            for boundary_params in [BoundaryParams::default(), channel()] {
+                let mut flow_box =
+                    FlowBox::init_with_params(40, 30, params(solver), boundary_params);
+                flow_box.add_solid_rect(15, 10, 5, 8);
+                for step in 0..5 {
+                    stir(&mut flow_box, step);
+                    assert!(flow_box.max_divergence() > 10.0);
+                    let report = flow_box.step(0.05);
+                    assert!(flow_box.max_divergence() < 1e-3);
+                    assert!(report.max_divergence < 1e-3);
+                }
+            }
+        }
+    }
 
-            let s1 = x - i0;
-            let s0 = 1.0 - s1;
-            let t1 = y - j0;
-            let t0 = 1.0 - t1;
+    #[test]
+    fn pressure_driven_flow_agrees_across_solvers() {
+        // Fixed pressures at either end of a channel accelerate the fluid by their gradient,
+        // 2.5 m/s² over the 0.4 m between them
+        let boundary_params = || BoundaryParams {
+            left: BoundaryType::PRESSURE(1.0).into(),
+            right: BoundaryType::PRESSURE(0.0).into(),
+            ..BoundaryParams::default()
+        };
+        for solver in [PressureSolver::Relaxation].into_iter().chain(SOLVERS) {
+            let fluid_params = FluidParams {
+                pressure_solver: solver,
+                ..FluidParams::default()
+            };
+            let mut flow_box: FlowBox =
+                FlowBox::init_with_params(40, 20, fluid_params, boundary_params());
+            for _ in 0..50 {
+                flow_box.step(0.01);
+            }
+            let speed = flow_box.vel_x[index(&20, &10, &flow_box.dim)];
+            assert!((speed - 1.25).abs() < 0.05);
+        }
+    }
 
-            let i0i = i0 as usize;
-            let i1i = i1 as usize;
-            let j0i = j0 as usize;
-            let j1i = j1 as usize;
+    #[test]
+    fn uniform_gravity_falls_between_open_edges() {
+        let boundary_params = || BoundaryParams {
+            top: BoundaryType::OUTLET.into(),
+            bottom: BoundaryType::OUTLET.into(),
+            ..BoundaryParams::default()
+        };
+        for uniform_gravity in [false, true] {
+            let fluid_params = FluidParams {
+                uniform_gravity,
+                ..params(PressureSolver::Multigrid(Cycle::V))
+            };
+            let mut flow_box: FlowBox =
+                FlowBox::init_with_params(20, 20, fluid_params, boundary_params());
+            for _ in 0..10 {
+                flow_box.step(0.01);
+            }
+            // Ten steps of 9.8 m/s² reach about 1 m/s, falling down the grid
+            let speed = flow_box.vel_y[index(&10, &10, &flow_box.dim)];
+            if uniform_gravity {
+                assert!((speed - 0.98).abs() < 0.05);
+            } else {
+                assert!(speed.abs() < 1e-3);
+            }
+        }
+    }
 
-            *v = (vals0[Self::index(&i0i, &j0i, dim).clamp(0, l)].mul(t0)
-                + vals0[Self::index(&i0i, &j1i, dim).clamp(0, l)].mul(t1))
-            .mul(s0)
-                + (vals0[Self::index(&i1i, &j0i, dim).clamp(0, l)].mul(t0)
-                    + vals0[Self::index(&i1i, &j1i, dim).clamp(0, l)].mul(t1))
-                .mul(s1);
-        });
+    #[test]
+    fn sponges_relax_thickness_rows() {
+        let thickness = 4;
+        for side in [Side::Top, Side::Bottom, Side::Left, Side::Right] {
+            let mut boundary_params = BoundaryParams::default();
+            let edge = match side {
+                Side::Top => &mut boundary_params.top,
+                Side::Bottom => &mut boundary_params.bottom,
+                Side::Left => &mut boundary_params.left,
+                Side::Right => &mut boundary_params.right,
+            };
+            edge.sponge = Some(Sponge::init(thickness, 10.0));
+            let mut flow_box: FlowBox =
+                FlowBox::init_with_params(20, 16, FluidParams::default(), boundary_params);
+            flow_box.vel_x.fill(1.0);
+            flow_box.apply_sponges(0.1);
 
-        Self::set_bound(bound, vals, dim);
-    }
-    // Returns index value for the x, y position
-    #[inline]
-    pub fn index(x: &usize, y: &usize, dim: &(usize, usize)) -> usize {
-        x + y * dim.0
+            // Walks in from the middle of the grid towards the edge the sponge lies along
+            let (w, h) = flow_box.dim;
+            let across = match side {
+                Side::Top | Side::Bottom => h - 2,
+                Side::Left | Side::Right => w - 2,
+            };
+            let mut last = 0.0;
+            for depth in (1..=across).rev() {
+                let (x, y) = match side {
+                    Side::Top => (w / 2, depth),
+                    Side::Bottom => (w / 2, h - 1 - depth),
+                    Side::Left => (depth, h / 2),
+                    Side::Right => (w - 1 - depth, h / 2),
+                };
+                let relaxed = 1.0 - flow_box.vel_x[index(&x, &y, &flow_box.dim)];
+                assert_eq!(relaxed > 0.0, depth <= thickness);
+                // The layer relaxes harder towards the edge
+                assert!(relaxed >= last);
+                last = relaxed;
+            }
+        }
     }
-    // Returns the x, y position given the index
-    #[inline]
-    pub fn pos(i: &usize, dim: &(usize, usize)) -> (usize, usize) {
-        (i % dim.0, i / dim.0)
+
+    #[test]
+    #[should_panic(expected = "must both have a PERIODIC base")]
+    fn periodic_base_needs_periodic_opposite() {
+        let boundary_params = BoundaryParams {
+            left: BoundaryType::PERIODIC.into(),
+            ..BoundaryParams::default()
+        };
+        let _: FlowBox = FlowBox::init_with_params(20, 16, FluidParams::default(), boundary_params);
     }
 }
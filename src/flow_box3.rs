@@ -0,0 +1,385 @@
+//! Defines fluid simulation over a three dimensional grid
+//!
+//! Takes the same steps as FlowBox through the same solver stages, extended along a third
+//! axis, so FluidParams applies as it does on FlowBox. The box is always closed by solid
+//! walls which slip as solid_slip sets, there are no inlets or outlets, and gravity may
+//! point along any of the three axes
+
+use glam::Vec3;
+use rayon::prelude::*;
+
+use super::flow_box::{FluidParams, StepReport, DEFAULT_CELL_SIZE};
+use super::grid::{
+    apply_body_forces, cfl, confine_vorticity, max_divergence, step_adaptive, Bound, Domain,
+    EdgeKind, Grid,
+};
+use super::real::{FieldValue, Real};
+
+/// A plane of cells through a FlowBox3
+#[derive(Clone, Copy)]
+pub enum Slice {
+    // Cells at this x, z runs across and y runs down
+    X(usize),
+    // Cells at this y, x runs across and z runs down
+    Y(usize),
+    // Cells at this z, x runs across and y runs down
+    Z(usize),
+}
+impl Slice {
+    /// Returns the width and height of the plane
+    pub fn dim(&self, dim: &(usize, usize, usize)) -> (usize, usize) {
+        match self {
+            Slice::X(_) => (dim.2, dim.1),
+            Slice::Y(_) => (dim.0, dim.2),
+            Slice::Z(_) => (dim.0, dim.1),
+        }
+    }
+    /// Returns the grid index of the cell at u across and v down the plane
+    pub fn index(&self, u: usize, v: usize, dim: &(usize, usize, usize)) -> usize {
+        let (x, y, z) = match *self {
+            Slice::X(x) => (x.min(dim.0 - 1), v, u),
+            Slice::Y(y) => (u, y.min(dim.1 - 1), v),
+            Slice::Z(z) => (u, v, z.min(dim.2 - 1)),
+        };
+        index(&x, &y, &z, dim)
+    }
+}
+
+/// A box which holds a three dimensional grid of fluid velocity vectors
+///
+/// Fields and time are held at the precision R like FlowBox, f32 by default
+pub struct FlowBox3<R: Real = f32> {
+    pub dim: (usize, usize, usize),
+    // Width, height and depth of each cell in metres
+    pub spacing: R::Vec3,
+
+    pub vel_x: Vec<R>,
+    vel_x0: Vec<R>,
+    pub vel_y: Vec<R>,
+    vel_y0: Vec<R>,
+    pub vel_z: Vec<R>,
+    vel_z0: Vec<R>,
+
+    pub density: Vec<R::Vec3>,
+    density0: Vec<R::Vec3>,
+
+    pub temperature: Vec<R>,
+    temperature0: Vec<R>,
+
+    // Pressure divided by density in m²/s², as left by the last projection
+    pub pressure: Vec<R>,
+
+    // Every edge cell is a wall slipping as solid_slip sets
+    edges: Vec<EdgeKind>,
+
+    solid: Vec<bool>,
+    // Solid cells never move, so the velocity of their surfaces is zero along every axis
+    solid_vel: Vec<R>,
+    solid_friction: Vec<f32>,
+
+    // Seconds simulated so far
+    time: R,
+
+    fluid_params: FluidParams<Vec3>,
+}
+impl<R: Real> FlowBox3<R> {
+    /* Initializing */
+    pub fn init(width: usize, height: usize, depth: usize) -> Self {
+        Self::init_with_params(width, height, depth, FluidParams::default())
+    }
+    pub fn init_with_params(
+        width: usize,
+        height: usize,
+        depth: usize,
+        fluid_params: FluidParams<Vec3>,
+    ) -> Self {
+        let cells = width * height * depth;
+        let ambient = R::from_f32(fluid_params.ambient_temperature);
+        let friction = fluid_params.solid_slip.friction();
+        FlowBox3 {
+            dim: (width, height, depth),
+            spacing: R::vec3(Vec3::splat(DEFAULT_CELL_SIZE)),
+            vel_x: vec![R::zero(); cells],
+            vel_x0: vec![R::zero(); cells],
+            vel_y: vec![R::zero(); cells],
+            vel_y0: vec![R::zero(); cells],
+            vel_z: vec![R::zero(); cells],
+            vel_z0: vec![R::zero(); cells],
+            density: vec![R::Vec3::splat(R::zero()); cells],
+            density0: vec![R::Vec3::splat(R::zero()); cells],
+            temperature: vec![ambient; cells],
+            temperature0: vec![ambient; cells],
+            pressure: vec![R::zero(); cells],
+            edges: vec![EdgeKind::Wall(friction); cells],
+            solid: vec![false; cells],
+            solid_vel: vec![R::zero(); cells],
+            solid_friction: vec![friction; cells],
+            time: R::zero(),
+            fluid_params,
+        }
+    }
+
+    /// Sets the width, height and depth of the whole grid in metres
+    pub fn set_size(&mut self, width: R, height: R, depth: R) {
+        self.spacing = [
+            width / R::from_usize(self.dim.0),
+            height / R::from_usize(self.dim.1),
+            depth / R::from_usize(self.dim.2),
+        ]
+        .into();
+    }
+    /// Returns the width, height and depth of the whole grid in metres
+    pub fn size(&self) -> R::Vec3 {
+        let [dx, dy, dz]: [R; 3] = self.spacing.into();
+        [
+            dx * R::from_usize(self.dim.0),
+            dy * R::from_usize(self.dim.1),
+            dz * R::from_usize(self.dim.2),
+        ]
+        .into()
+    }
+
+    /// Returns the seconds simulated so far
+    pub fn time(&self) -> R {
+        self.time
+    }
+
+    /* Interacting with Fluids */
+    pub fn add_fluid_density(&mut self, x: usize, y: usize, z: usize, color: [f32; 4]) {
+        let i = self.clamped_index(x, y, z);
+        self.density[i] += R::vec3(Vec3::new(color[0], color[1], color[2]));
+    }
+    /// Adds velocity in metres per second to a cell
+    pub fn add_fluid_velocity(&mut self, x: usize, y: usize, z: usize, vel: R::Vec3) {
+        let i = self.clamped_index(x, y, z);
+        let [vx, vy, vz]: [R; 3] = vel.into();
+        self.vel_x[i] += vx;
+        self.vel_y[i] += vy;
+        self.vel_z[i] += vz;
+    }
+    pub fn scale_fluid_density(&mut self, mag: R) {
+        self.density.par_iter_mut().for_each(|d| *d *= mag);
+    }
+    /// Adds heat to a cell, negative amounts cool it
+    pub fn add_heat(&mut self, x: usize, y: usize, z: usize, amount: R) {
+        let i = self.clamped_index(x, y, z);
+        self.temperature[i] += amount;
+    }
+    /// Sets the temperature of a cell, calling this every step holds it fixed like a heated wall
+    pub fn set_temperature(&mut self, x: usize, y: usize, z: usize, temperature: R) {
+        let i = self.clamped_index(x, y, z);
+        self.temperature[i] = temperature;
+    }
+    // Index of the cell nearest x, y, z within the grid
+    fn clamped_index(&self, x: usize, y: usize, z: usize) -> usize {
+        index(
+            &x.min(self.dim.0 - 1),
+            &y.min(self.dim.1 - 1),
+            &z.min(self.dim.2 - 1),
+            &self.dim,
+        )
+    }
+
+    /* Obstacles */
+    /// Marks a cell as solid or fluid, cells on the outer walls are ignored
+    pub fn set_solid(&mut self, x: usize, y: usize, z: usize, solid: bool) {
+        if !(1..self.dim.0 - 1).contains(&x)
+            || !(1..self.dim.1 - 1).contains(&y)
+            || !(1..self.dim.2 - 1).contains(&z)
+        {
+            return;
+        }
+        let i = index(&x, &y, &z, &self.dim);
+        self.solid[i] = solid;
+        if solid {
+            let ambient = R::from_f32(self.fluid_params.ambient_temperature);
+            self.vel_x[i] = R::zero();
+            self.vel_x0[i] = R::zero();
+            self.vel_y[i] = R::zero();
+            self.vel_y0[i] = R::zero();
+            self.vel_z[i] = R::zero();
+            self.vel_z0[i] = R::zero();
+            self.density[i] = R::Vec3::splat(R::zero());
+            self.density0[i] = R::Vec3::splat(R::zero());
+            self.temperature[i] = ambient;
+            self.temperature0[i] = ambient;
+        }
+    }
+    /// Returns whether the cell at x, y, z is solid
+    pub fn is_solid(&self, x: usize, y: usize, z: usize) -> bool {
+        x < self.dim.0
+            && y < self.dim.1
+            && z < self.dim.2
+            && self.solid[index(&x, &y, &z, &self.dim)]
+    }
+    /// Fills every cell whose center lies within the sphere with solid
+    pub fn add_solid_sphere(&mut self, center: Vec3, radius: f32) {
+        for i in 0..self.solid.len() {
+            let (x, y, z) = pos(&i, &self.dim);
+            if Vec3::new(x as f32, y as f32, z as f32).distance_squared(center) <= radius * radius {
+                self.set_solid(x, y, z, true);
+            }
+        }
+    }
+    /// Returns the largest divergence in any fluid cell per second measured by central
+    /// differences over two cells, see FlowBox::max_divergence
+    pub fn max_divergence(&self) -> R {
+        max_divergence(
+            &Grid::new(self.dim.into()),
+            self.spacing.into(),
+            &self.solid,
+            [&self.solid_vel; 3],
+            [&self.vel_x, &self.vel_y, &self.vel_z],
+        )
+    }
+
+    pub fn step(&mut self, dt: R) -> StepReport {
+        let mut report = StepReport::default();
+
+        apply_body_forces(
+            [&mut self.vel_x, &mut self.vel_y, &mut self.vel_z],
+            &self.density,
+            &self.temperature,
+            &self.solid,
+            &self.fluid_params,
+            dt,
+        );
+        confine_vorticity(
+            &Grid::new(self.dim.into()),
+            self.spacing.into(),
+            &self.solid,
+            [&mut self.vel_x, &mut self.vel_y, &mut self.vel_z],
+            self.fluid_params.vorticity_confinement,
+            dt,
+        );
+
+        let domain = Domain {
+            grid: Grid::new(self.dim.into()),
+            spacing: self.spacing.into(),
+            periodic: [false; 3],
+            edges: &self.edges,
+            hold_pressure: false,
+            solid: &self.solid,
+            solid_vel: [&self.solid_vel; 3],
+            solid_friction: &self.solid_friction,
+        };
+        let params = &self.fluid_params;
+
+        for (axis, vel, vel0) in [
+            (0, &mut self.vel_x0, &self.vel_x),
+            (1, &mut self.vel_y0, &self.vel_y),
+            (2, &mut self.vel_z0, &self.vel_z),
+        ] {
+            report.add_diffuse(domain.diffuse(
+                &Bound::Velocity(axis),
+                vel,
+                vel0,
+                params.viscosity,
+                dt,
+                params,
+            ));
+        }
+
+        let solve = domain.project(
+            [&mut self.vel_x0, &mut self.vel_y0, &mut self.vel_z0],
+            &mut self.pressure,
+            &mut self.vel_x,
+            dt,
+            params,
+        );
+        report.add_pressure(solve);
+
+        let vel0 = [&self.vel_x0[..], &self.vel_y0[..], &self.vel_z0[..]];
+        for (axis, vals, vals0) in [
+            (0, &mut self.vel_x, &self.vel_x0),
+            (1, &mut self.vel_y, &self.vel_y0),
+            (2, &mut self.vel_z, &self.vel_z0),
+        ] {
+            domain.advect(&Bound::Velocity(axis), vals, vals0, vel0, dt, params);
+        }
+        let solve = domain.project(
+            [&mut self.vel_x, &mut self.vel_y, &mut self.vel_z],
+            &mut self.pressure,
+            &mut self.vel_x0,
+            dt,
+            params,
+        );
+        report.add_pressure(solve);
+        report.max_divergence = self.max_divergence().as_f32();
+
+        let vel = [&self.vel_x[..], &self.vel_y[..], &self.vel_z[..]];
+        report.add_diffuse(domain.diffuse(
+            &Bound::Neither,
+            &mut self.density0,
+            &self.density,
+            params.diffusion_rate,
+            dt,
+            params,
+        ));
+        domain.advect(
+            &Bound::Neither,
+            &mut self.density,
+            &self.density0,
+            vel,
+            dt,
+            params,
+        );
+
+        report.add_diffuse(domain.diffuse(
+            &Bound::Neither,
+            &mut self.temperature0,
+            &self.temperature,
+            params.conductivity,
+            dt,
+            params,
+        ));
+        domain.advect(
+            &Bound::Neither,
+            &mut self.temperature,
+            &self.temperature0,
+            vel,
+            dt,
+            params,
+        );
+        self.time += dt;
+        report
+    }
+    /// Steps forward by frame_dt in as many substeps as are needed to keep the CFL number
+    /// within max_cfl, returning the reports of every substep merged and how many were taken
+    pub fn step_adaptive(&mut self, frame_dt: R) -> (StepReport, usize) {
+        let (max_cfl, max_substeps) = (self.fluid_params.max_cfl, self.fluid_params.max_substeps);
+        step_adaptive(self, frame_dt, max_cfl, max_substeps, Self::cfl, Self::step)
+    }
+    /// Returns the largest number of cells fluid would cross in a step of dt
+    pub fn cfl(&self, dt: R) -> R {
+        cfl(
+            [&self.vel_x, &self.vel_y, &self.vel_z],
+            self.spacing.into(),
+            dt,
+        )
+    }
+}
+
+impl FlowBox3 {
+    /// Returns the index of the cell at x, y, z, same as the free index
+    #[inline]
+    pub fn index(x: &usize, y: &usize, z: &usize, dim: &(usize, usize, usize)) -> usize {
+        index(x, y, z, dim)
+    }
+    /// Returns the x, y, z position given the index, same as the free pos
+    #[inline]
+    pub fn pos(i: &usize, dim: &(usize, usize, usize)) -> (usize, usize, usize) {
+        pos(i, dim)
+    }
+}
+
+/// Returns the index of the cell at x, y, z
+#[inline]
+pub fn index(x: &usize, y: &usize, z: &usize, dim: &(usize, usize, usize)) -> usize {
+    x + (y + z * dim.1) * dim.0
+}
+/// Returns the x, y, z position given the index
+#[inline]
+pub fn pos(i: &usize, dim: &(usize, usize, usize)) -> (usize, usize, usize) {
+    (i % dim.0, (i / dim.0) % dim.1, i / (dim.0 * dim.1))
+}
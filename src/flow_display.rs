@@ -2,7 +2,9 @@
 
 use std::f32::consts::PI;
 
-use super::flow_box::FlowBox;
+use super::flow_box::{pos, FlowBox};
+use super::flow_box3::{self, FlowBox3, Slice};
+use super::real::Real;
 use lazy_static::lazy_static;
 use macroquad::prelude::*;
 
@@ -12,6 +14,7 @@ pub enum DisplayMode {
     DensityBlackWhite,
     DensityColor,
     VelocityBlackWhite,
+    Temperature,
 }
 
 /// Flags for debugging fluid sim
@@ -28,6 +31,7 @@ lazy_static! {
         Color::from_hex(0xffd429),
         Color::from_hex(0xff3729)
     ];
+    static ref SOLID_COLOR: Color = Color::from_hex(0x5a5a5a);
 }
 
 /// Displays a FlowBox
@@ -54,7 +58,7 @@ impl FlowDisplay {
     }
     /// Returns the FlowBox grid coords of mouse
     pub fn get_mouse_cord(&self, dim: &(usize, usize)) -> (usize, usize) {
-        let (block_size_x, block_size_y) = self.get_block_size(&dim);
+        let (block_size_x, block_size_y) = self.get_block_size(dim);
         let mouse_pos: Vec2 = mouse_position().into();
         let pos = mouse_pos / Vec2::new(block_size_x, block_size_y);
         (
@@ -87,34 +91,25 @@ impl FlowDisplay {
         }
     }
     /// Displays fluid onto the screen
-    pub fn display(&self, flow_box: &FlowBox) {
+    pub fn display<R: Real>(&self, flow_box: &FlowBox<R>) {
         let dim = flow_box.dim;
 
         let (block_size_x, block_size_y) = self.get_block_size(&dim);
 
-        (0..dim.0 * dim.1).into_iter().for_each(|i| {
-            let (x, y) = FlowBox::pos(&i, &dim);
+        let (t_min, t_range) = Self::temperature_range(&flow_box.temperature);
+
+        (0..dim.0 * dim.1).for_each(|i| {
+            let (x, y) = pos(&i, &dim);
 
             // Getting the correct color depending on display mode
-            let color = match self.mode {
-                DisplayMode::DensityColor => Color::new(
-                    flow_box.density[i].x,
-                    flow_box.density[i].y,
-                    flow_box.density[i].z,
-                    1.0,
-                ),
-                DisplayMode::DensityBlackWhite => {
-                    let avg =
-                        (flow_box.density[i].x + flow_box.density[i].y + flow_box.density[i].z)
-                            / 3.0;
-                    Color::new(avg, avg, avg, 1.0)
-                }
-                DisplayMode::VelocityBlackWhite => {
-                    let vx = flow_box.vel_x[i].clamp(-100.0, 100.0) as f32;
-                    let vy = flow_box.vel_y[i].clamp(-100.0, 100.0) as f32;
-                    let m = Vec2::new(vx, vy).length_squared();
-                    Color::new(m, m, m, 1.0)
-                }
+            let color = if flow_box.is_solid(x, y) {
+                *SOLID_COLOR
+            } else {
+                self.cell_color(
+                    Self::single::<R>(flow_box.density[i]),
+                    ::glam::Vec3::new(flow_box.vel_x[i].as_f32(), flow_box.vel_y[i].as_f32(), 0.0),
+                    ((flow_box.temperature[i] - t_min) / t_range).as_f32(),
+                )
             };
 
             draw_rectangle(
@@ -129,4 +124,79 @@ impl FlowDisplay {
             draw_text(&format!("FPS: {}", get_fps()), 20.0, 20.0, 30.0, WHITE);
         }
     }
+    /// Displays one plane of cells from a FlowBox3 onto the screen
+    pub fn display_slice<R: Real>(&self, flow_box: &FlowBox3<R>, slice: Slice) {
+        let dim = slice.dim(&flow_box.dim);
+
+        let (block_size_x, block_size_y) = self.get_block_size(&dim);
+
+        let (t_min, t_range) = Self::temperature_range(&flow_box.temperature);
+
+        (0..dim.0 * dim.1).for_each(|c| {
+            let (u, v) = pos(&c, &dim);
+            let i = slice.index(u, v, &flow_box.dim);
+            let (x, y, z) = flow_box3::pos(&i, &flow_box.dim);
+
+            let color = if flow_box.is_solid(x, y, z) {
+                *SOLID_COLOR
+            } else {
+                self.cell_color(
+                    Self::single::<R>(flow_box.density[i]),
+                    ::glam::Vec3::new(
+                        flow_box.vel_x[i].as_f32(),
+                        flow_box.vel_y[i].as_f32(),
+                        flow_box.vel_z[i].as_f32(),
+                    ),
+                    ((flow_box.temperature[i] - t_min) / t_range).as_f32(),
+                )
+            };
+
+            draw_rectangle(
+                u as f32 * block_size_x,
+                v as f32 * block_size_y,
+                block_size_x,
+                block_size_y,
+                color,
+            );
+        });
+        if self.flags & flags::DISPLAY_FPS != 0 {
+            draw_text(&format!("FPS: {}", get_fps()), 20.0, 20.0, 30.0, WHITE);
+        }
+    }
+    // Temperatures are shown relative to the coldest and hottest cells, returns the coldest
+    // and the range up to the hottest
+    fn temperature_range<R: Real>(temperature: &[R]) -> (R, R) {
+        let (t_min, t_max) = temperature
+            .iter()
+            .fold((R::max_value(), R::min_value()), |(lo, hi), &t| {
+                (lo.min(t), hi.max(t))
+            });
+        (t_min, (t_max - t_min).max(R::epsilon()))
+    }
+    // Converts a dye color at the sims precision to single precision
+    fn single<R: Real>(v: R::Vec3) -> ::glam::Vec3 {
+        let v: [R; 3] = v.into();
+        ::glam::Vec3::from_array(v.map(R::as_f32))
+    }
+    // Color of a fluid cell in the current mode, temperature is given between 0 and 1. Grid
+    // values use the sims own glam rather than the version macroquad brings
+    fn cell_color(&self, density: ::glam::Vec3, vel: ::glam::Vec3, temperature: f32) -> Color {
+        match self.mode {
+            DisplayMode::DensityColor => Color::new(density.x, density.y, density.z, 1.0),
+            DisplayMode::DensityBlackWhite => {
+                let avg = density.element_sum() / 3.0;
+                Color::new(avg, avg, avg, 1.0)
+            }
+            DisplayMode::VelocityBlackWhite => {
+                let m = vel
+                    .clamp(::glam::Vec3::splat(-100.0), ::glam::Vec3::splat(100.0))
+                    .length_squared();
+                Color::new(m, m, m, 1.0)
+            }
+            DisplayMode::Temperature => {
+                let t = temperature;
+                Color::new(t, 0.2 * (1.0 - t), 1.0 - t, 1.0)
+            }
+        }
+    }
 }
@@ -0,0 +1,1019 @@
+//! Defines grids of cells along any number of axes and the solver stages shared by the
+//! two dimensional FlowBox and the three dimensional FlowBox3
+//!
+//! Every stage works over a Domain, the layout of the grid along with how its edges and
+//! solid cells are filled. Cells are stored with the first axis varying fastest and the
+//! outermost ring of cells along each axis holds ghost values mirroring the fluid inside
+
+use std::array;
+use std::marker::PhantomData;
+use std::ops::{Add, Mul, Sub};
+
+use rayon::prelude::*;
+
+use super::flow_box::{AdvectionScheme, Backtrace, FluidParams, Interpolation, StepReport};
+use super::pressure::{Cell, Poisson, PressureSolver, SolveLimits, SolveReport};
+use super::real::{FieldValue, Real};
+
+/// Shape of a grid of cells along N axes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Grid<const N: usize> {
+    pub(crate) dim: [usize; N],
+    // Distance through memory between neighbors along each axis
+    pub(crate) strides: [usize; N],
+}
+impl<const N: usize> Grid<N> {
+    pub(crate) fn new(dim: [usize; N]) -> Self {
+        let mut strides = [1; N];
+        for axis in 1..N {
+            strides[axis] = strides[axis - 1] * dim[axis - 1];
+        }
+        Grid { dim, strides }
+    }
+    /// Returns the number of cells
+    pub(crate) fn len(&self) -> usize {
+        self.dim.iter().product()
+    }
+    /// Returns the index of the cell at the given coordinates
+    pub(crate) fn index(&self, coords: [usize; N]) -> usize {
+        coords.iter().zip(self.strides).map(|(c, s)| c * s).sum()
+    }
+    /// Returns the coordinates of the cell at an index
+    pub(crate) fn coords(&self, i: usize) -> [usize; N] {
+        array::from_fn(|axis| i / self.strides[axis] % self.dim[axis])
+    }
+    /// Returns whether a cell lies within the ring of edge cells
+    pub(crate) fn is_interior(&self, i: usize) -> bool {
+        self.coords(i)
+            .iter()
+            .zip(self.dim)
+            .all(|(c, n)| (1..n - 1).contains(c))
+    }
+    /// Returns every cell whose coordinate along each axis is the one given, or any interior
+    /// coordinate for axes given None
+    pub(crate) fn cells(
+        &self,
+        fixed: [Option<usize>; N],
+    ) -> impl IndexedParallelIterator<Item = usize> {
+        let grid = *self;
+        let count = (0..N)
+            .filter(|&axis| fixed[axis].is_none())
+            .map(|axis| grid.dim[axis] - 2)
+            .product();
+        (0..count).into_par_iter().map(move |mut rest: usize| {
+            let mut i = 0;
+            for (axis, fixed) in fixed.iter().enumerate() {
+                let c = fixed.unwrap_or_else(|| {
+                    let inner = grid.dim[axis] - 2;
+                    let c = 1 + rest % inner;
+                    rest /= inner;
+                    c
+                });
+                i += c * grid.strides[axis];
+            }
+            i
+        })
+    }
+}
+
+/// A position in grid space measured in cells
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Point<R, const N: usize>(pub(crate) [R; N]);
+impl<R: Real, const N: usize> Add for Point<R, N> {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Point(array::from_fn(|axis| self.0[axis] + other.0[axis]))
+    }
+}
+impl<R: Real, const N: usize> Sub for Point<R, N> {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Point(array::from_fn(|axis| self.0[axis] - other.0[axis]))
+    }
+}
+impl<R: Real, const N: usize> Mul<R> for Point<R, N> {
+    type Output = Self;
+    fn mul(self, scale: R) -> Self {
+        Point(self.0.map(|v| v * scale))
+    }
+}
+impl<R: Real, const N: usize> FieldValue<R> for Point<R, N> {
+    fn splat(v: R) -> Self {
+        Point([v; N])
+    }
+    fn component_min(self, other: Self) -> Self {
+        Point(array::from_fn(|axis| self.0[axis].min(other.0[axis])))
+    }
+    fn component_max(self, other: Self) -> Self {
+        Point(array::from_fn(|axis| self.0[axis].max(other.0[axis])))
+    }
+    fn magnitude(self) -> R {
+        self.0.iter().fold(R::zero(), |m, v| m.max(v.abs()))
+    }
+}
+
+/// Represents what type of operation is being used on elements
+#[derive(PartialEq, Eq)]
+pub(crate) enum Bound {
+    Neither,
+    // Velocity along an axis, reflected off surfaces facing along it
+    Velocity(usize),
+    // Pressure, held at zero along outflows
+    Pressure,
+}
+
+/// How the edge cells of the sim are filled from the cells just inside
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum EdgeKind {
+    // Mirrors the cell inside, holding back flow along it by the walls friction
+    Wall(f32),
+    // Like a wall which sticks, but drags fluid along at the given speed
+    MovingWall(f32),
+    // Passes the normal velocity in and stops flow along it
+    Inlet,
+    // Copies the cell inside so values have zero gradient, pressure is held at zero
+    Outflow,
+    // Values are carried out at the speed of the flow reaching the edge, pressure is held at zero
+    ConvectiveOutflow,
+    // Copies the cell inside like an outflow, pressure is held at the given value
+    FixedPressure(f32),
+}
+
+/// Raw view of a grid shared between the threads of a red black half sweep
+pub(crate) struct SharedCells<'a, T> {
+    ptr: *mut T,
+    len: usize,
+    _vals: PhantomData<&'a mut [T]>,
+}
+unsafe impl<T: Send> Send for SharedCells<'_, T> {}
+unsafe impl<T: Sync> Sync for SharedCells<'_, T> {}
+impl<'a, T: Copy> SharedCells<'a, T> {
+    pub(crate) fn new(vals: &'a mut [T]) -> Self {
+        SharedCells {
+            ptr: vals.as_mut_ptr(),
+            len: vals.len(),
+            _vals: PhantomData,
+        }
+    }
+    /// Caller must ensure no other thread is writing cell i
+    pub(crate) unsafe fn read(&self, i: usize) -> T {
+        assert!(i < self.len);
+        *self.ptr.add(i)
+    }
+    /// Caller must ensure no other thread is reading or writing cell i
+    pub(crate) unsafe fn write(&self, i: usize, val: T) {
+        assert!(i < self.len);
+        *self.ptr.add(i) = val;
+    }
+}
+
+/// Borrowed view of the grid geometry every solver stage works over
+pub(crate) struct Domain<'a, R: Real, const N: usize> {
+    pub(crate) grid: Grid<N>,
+    // Size of each cell along each axis in metres
+    pub(crate) spacing: [R; N],
+    // Whether each axis wraps around, edge cells then mirror the opposite interior
+    pub(crate) periodic: [bool; N],
+    pub(crate) edges: &'a [EdgeKind],
+    // Whether openings at a fixed pressure push on the flow, only the last projection of a
+    // step holds them so the flow is pushed once per step
+    pub(crate) hold_pressure: bool,
+    pub(crate) solid: &'a [bool],
+    // Velocity of the surface covering each solid cell along each axis
+    pub(crate) solid_vel: [&'a [R]; N],
+    // Friction of the surface covering each solid cell, 0 slides freely and 1 sticks
+    pub(crate) solid_friction: &'a [f32],
+}
+impl<R: Real, const N: usize> Domain<'_, R, N> {
+    // Whether a cell is fluid within the ring of edge cells
+    fn fluid_interior(&self, i: usize) -> bool {
+        !self.solid[i] && self.grid.is_interior(i)
+    }
+    /// Fills the edge cells and solid cells from the fluid beside them
+    pub(crate) fn set_bound<T>(&self, b: &Bound, vals: &mut [T])
+    where
+        T: FieldValue<R>,
+    {
+        let grid = self.grid;
+
+        // Cells outside the interior along a single axis are filled from the cell inside
+        // them as their edge kind sets, or the cell across the grid when wrapping
+        for axis in 0..N {
+            let (n, stride) = (grid.dim[axis], grid.strides[axis]);
+            let normal = b == &Bound::Velocity(axis);
+            let tangent = matches!(b, Bound::Velocity(_)) && !normal;
+            let edge = |ghost: usize, inner: usize, across: usize| {
+                if self.periodic[axis] {
+                    return vals[across];
+                }
+                match (self.edges[ghost], b) {
+                    // Flow into a wall is reflected, flow along it is kept by as much as it slips
+                    (EdgeKind::Wall(_) | EdgeKind::MovingWall(_), _) if normal => {
+                        vals[inner].mul(-R::one())
+                    }
+                    (EdgeKind::Wall(friction), _) if tangent => {
+                        vals[inner].mul(R::from_f32(1.0 - 2.0 * friction))
+                    }
+                    (EdgeKind::MovingWall(speed), _) if tangent => {
+                        T::splat(R::from_f32(2.0 * speed)) - vals[inner]
+                    }
+                    (EdgeKind::Inlet, _) if tangent => vals[inner].mul(-R::one()),
+                    (EdgeKind::Wall(_) | EdgeKind::MovingWall(_) | EdgeKind::Inlet, _) => {
+                        vals[inner]
+                    }
+                    (EdgeKind::FixedPressure(value), Bound::Pressure) if self.hold_pressure => {
+                        T::splat(R::from_f32(value))
+                    }
+                    (_, Bound::Pressure) => T::splat(R::zero()),
+                    (EdgeKind::Outflow | EdgeKind::FixedPressure(_), _) => vals[inner],
+                    (EdgeKind::ConvectiveOutflow, _) => vals[ghost],
+                }
+            };
+            let side = |at: usize, inner: usize, across: usize| {
+                let mut fixed = [None; N];
+                fixed[axis] = Some(at);
+                let edge = &edge;
+                grid.cells(fixed).map(move |ghost| {
+                    let base = ghost - at * stride;
+                    (
+                        ghost,
+                        edge(ghost, base + inner * stride, base + across * stride),
+                    )
+                })
+            };
+            let filled: Vec<(usize, T)> = side(0, 1, n - 2).chain(side(n - 1, n - 2, 1)).collect();
+            for (ghost, v) in filled {
+                vals[ghost] = v;
+            }
+        }
+
+        // Cells outside along several axes wrap along the first periodic one, otherwise they
+        // average the cells a step further in along each, which are always filled first
+        for outside in 2..=N {
+            for axes in (0..1usize << N).filter(|m| m.count_ones() as usize == outside) {
+                for far in (0..1usize << N).filter(|s| s & !axes == 0) {
+                    let fixed = array::from_fn(|axis| {
+                        (axes >> axis & 1 == 1).then(|| {
+                            if far >> axis & 1 == 1 {
+                                grid.dim[axis] - 1
+                            } else {
+                                0
+                            }
+                        })
+                    });
+                    let ghosts: Vec<usize> = grid.cells(fixed).collect();
+                    for ghost in ghosts {
+                        let step = |axis: usize, by: usize| {
+                            if far >> axis & 1 == 1 {
+                                ghost - by * grid.strides[axis]
+                            } else {
+                                ghost + by * grid.strides[axis]
+                            }
+                        };
+                        let along = (0..N).filter(|axis| axes >> axis & 1 == 1);
+                        vals[ghost] = match along.clone().find(|&axis| self.periodic[axis]) {
+                            Some(axis) => vals[step(axis, grid.dim[axis] - 2)],
+                            None => along
+                                .fold(T::splat(R::zero()), |sum, axis| sum + vals[step(axis, 1)])
+                                .mul(R::from_usize(outside).recip()),
+                        };
+                    }
+                }
+            }
+        }
+
+        // Deals with solid cells inside the grid, velocities are reflected about the obstacles
+        // own velocity so fluid moves with its surface while other values are mirrored, flow
+        // along the surface is only held back by as much friction as the surface has
+        let filled: Vec<(usize, T)> = (0..vals.len())
+            .into_par_iter()
+            .filter(|&i| self.solid[i])
+            .map(|i| {
+                let wall = match b {
+                    Bound::Velocity(axis) => T::splat(self.solid_vel[*axis][i]),
+                    Bound::Neither | Bound::Pressure => T::splat(R::zero()),
+                };
+                let slip = R::from_f32(1.0 - 2.0 * self.solid_friction[i]);
+                let mut sum = T::splat(R::zero());
+                let mut count = 0;
+                for axis in 0..N {
+                    let stride = grid.strides[axis];
+                    for n in [i + stride, i - stride] {
+                        if self.solid[n] {
+                            continue;
+                        }
+                        let dir = match b {
+                            Bound::Velocity(v) if *v == axis => -R::one(),
+                            Bound::Velocity(_) => slip,
+                            Bound::Neither | Bound::Pressure => R::one(),
+                        };
+                        sum = sum + (vals[n] - wall).mul(dir);
+                        count += 1;
+                    }
+                }
+                let v = if count == 0 {
+                    wall
+                } else {
+                    wall + sum.mul(R::from_usize(count).recip())
+                };
+                (i, v)
+            })
+            .collect();
+        for (i, v) in filled {
+            vals[i] = v;
+        }
+    }
+    // Sum of the neighbors of a cell along each axis weighted by a
+    fn neighbor_sum<T, F>(&self, i: usize, a: &[R; N], read: F) -> T
+    where
+        T: FieldValue<R>,
+        F: Fn(usize) -> T,
+    {
+        (0..N).fold(T::splat(R::zero()), |sum, axis| {
+            let stride = self.grid.strides[axis];
+            sum + (read(i + stride) + read(i - stride)).mul(a[axis])
+        })
+    }
+    /// Linear solver red black Gauss Seidel method with successive over relaxation
+    ///
+    /// Interior cells are split like a checkerboard, each cell only reads neighbors of the
+    /// other color so every half sweep updates one color in place and in parallel
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn lin_solve<T>(
+        &self,
+        bound: &Bound,
+        vals: &mut [T],
+        vals0: &[T],
+        a: [R; N],
+        c: R,
+        omega: R,
+        limits: &SolveLimits,
+    ) -> SolveReport
+    where
+        T: FieldValue<R>,
+    {
+        let grid = self.grid;
+        let c_recip = c.recip();
+        let scale = (0..vals0.len())
+            .into_par_iter()
+            .filter(|&i| self.fluid_interior(i))
+            .map(|i| vals0[i].magnitude())
+            .reduce(R::zero, R::max);
+        // Measures b - A x over the fluid cells once a sweep is done and the edges are current
+        let residual = |vals: &[T]| {
+            (0..vals.len())
+                .into_par_iter()
+                .filter(|&i| self.fluid_interior(i))
+                .map(|i| {
+                    let rhs = vals0[i] + self.neighbor_sum(i, &a, |j| vals[j]);
+                    (rhs - vals[i].mul(c)).magnitude()
+                })
+                .reduce(R::zero, R::max)
+        };
+        // Rows of interior cells along the first axis, given by the cell where each starts
+        let mut row_starts = [None; N];
+        row_starts[0] = Some(0);
+
+        let mut report = SolveReport::default();
+        for iteration in 1..=limits.max_iters {
+            for color in 0..2 {
+                let cells = SharedCells::new(vals);
+                grid.cells(row_starts).for_each(|row| {
+                    let parity: usize = grid.coords(row).iter().sum();
+                    let start = 1 + (parity + 1 + color) % 2;
+                    for x in (start..grid.dim[0] - 1).step_by(2) {
+                        let i = row + x;
+                        if self.solid[i] {
+                            continue;
+                        }
+                        // SAFETY: cell i has this half sweep's color and its neighbors along
+                        // every axis have the other, so no cell read here is written by
+                        // another thread and each cell is written by one thread only
+                        unsafe {
+                            let rhs = vals0[i] + self.neighbor_sum(i, &a, |j| cells.read(j));
+                            let old = cells.read(i);
+                            cells.write(i, old + (rhs.mul(c_recip) - old).mul(omega));
+                        }
+                    }
+                });
+            }
+            self.set_bound(bound, vals);
+
+            report.iterations = iteration;
+            if limits.tolerance > 0.0 {
+                report = SolveReport::relative(iteration, residual(vals), scale);
+                if report.residual <= limits.tolerance {
+                    return report;
+                }
+            }
+        }
+        SolveReport::relative(report.iterations, residual(vals), scale)
+    }
+    /// Diffuses out values over a larger area, implicitly so any rate is stable
+    pub(crate) fn diffuse<T, G>(
+        &self,
+        b: &Bound,
+        vals: &mut [T],
+        vals0: &[T],
+        diff: f32,
+        dt: R,
+        params: &FluidParams<G>,
+    ) -> SolveReport
+    where
+        T: FieldValue<R>,
+    {
+        let diff = R::from_f32(diff);
+        let a = self.spacing.map(|d| dt * diff / (d * d));
+        // Starting from the undiffused values keeps what the flow carried into outflow edges
+        vals.copy_from_slice(vals0);
+        self.lin_solve(
+            b,
+            vals,
+            vals0,
+            a,
+            R::one() + R::from_f32(2.0) * a.iter().copied().sum::<R>(),
+            R::from_f32(params.sor_omega),
+            &params.diffuse_limits(),
+        )
+    }
+    /// Solves for the pressure whose gradient removes divergence, pressure here is divided
+    /// by density. The pressure already in p, left by the last projection, is the first guess
+    ///
+    /// Divergence and the pressure gradient are both central differences, so pressure is
+    /// solved with the wide Laplacian they make up together and a converged solve leaves no
+    /// divergence. Walls and solids mirror the flow beside them and take the pressure of the
+    /// cell beside them, which is how the Laplacian treats them too
+    pub(crate) fn project<G>(
+        &self,
+        vel: [&mut [R]; N],
+        p: &mut [R],
+        div: &mut [R],
+        dt: R,
+        params: &FluidParams<G>,
+    ) -> SolveReport {
+        let grid = self.grid;
+        let half_recip = self.spacing.map(|d| (d + d).recip());
+        let dt_recip = dt.recip();
+
+        {
+            let vel = vel.each_ref().map(|v| &**v);
+            div.par_iter_mut().enumerate().for_each(|(i, v)| {
+                *v = if self.fluid_interior(i) {
+                    let solid_vel = &self.solid_vel;
+                    -divergence(&grid, &half_recip, self.solid, solid_vel, &vel, i) * dt_recip
+                } else {
+                    R::zero()
+                };
+            });
+        }
+
+        // Edge cells are solved along with the interior so their kind sets how pressure is
+        // held there, except along periodic axes whose edge cells only mirror the far side
+        let wraps = self.periodic.map(usize::from);
+        let solved = Grid::new(array::from_fn(|axis| grid.dim[axis] - 2 * wraps[axis]));
+        let outer = |i: usize| {
+            let coords = solved.coords(i);
+            grid.index(array::from_fn(|axis| coords[axis] + wraps[axis]))
+        };
+        let cells: Vec<Cell<R>> = (0..solved.len())
+            .into_par_iter()
+            .map(|i| self.pressure_cell(outer(i)))
+            .collect();
+        let poisson = Poisson::new(solved, &cells, self.spacing, self.periodic).with_reach(2);
+        let b: Vec<R> = (0..solved.len()).map(|i| div[outer(i)]).collect();
+        let mut solved_p: Vec<R> = (0..solved.len()).map(|i| p[outer(i)]).collect();
+        let limits = params.pressure_limits();
+        let report = match &params.pressure_solver {
+            PressureSolver::Relaxation => {
+                poisson.solve_relaxation(&mut solved_p, &b, R::from_f32(params.sor_omega), &limits)
+            }
+            PressureSolver::ConjugateGradient(preconditioner) => {
+                poisson.solve_cg(&mut solved_p, &b, preconditioner, &limits)
+            }
+            PressureSolver::Multigrid(cycle) => {
+                poisson.solve_multigrid(&mut solved_p, &b, cycle, &limits)
+            }
+        };
+        for (i, v) in solved_p.into_iter().enumerate() {
+            if cells[i] == Cell::Fluid {
+                p[outer(i)] = v;
+            }
+        }
+        self.set_bound(&Bound::Pressure, p);
+        let p = &*p;
+
+        // Pressure on the far side of a neighbor as the solve took it, walls and solids take
+        // the pressure of the cell itself and nothing pushes through an inflow
+        let side = |i: usize, n: usize| match self.pressure_cell(n) {
+            Cell::Fluid => Some(p[n]),
+            Cell::Wall => Some(p[i]),
+            Cell::Inflow => None,
+            Cell::Open(value) => Some(value),
+        };
+        for (axis, vel) in vel.into_iter().enumerate() {
+            let (n, stride) = (grid.dim[axis], grid.strides[axis]);
+            // Flow through openings is driven by the fixed pressure outside, filling their
+            // edge cells from inside would undo it
+            let openings: Vec<(usize, R)> = if self.periodic[axis] {
+                Vec::new()
+            } else {
+                [(0, 1), (n - 1, n - 2)]
+                    .into_iter()
+                    .flat_map(|(at, inner)| {
+                        let mut fixed = [None; N];
+                        fixed[axis] = Some(at);
+                        let vel = &*vel;
+                        grid.cells(fixed)
+                            .filter_map(move |ghost| {
+                                let inner = ghost - at * stride + inner * stride;
+                                let Cell::Open(value) = self.pressure_cell(ghost) else {
+                                    return None;
+                                };
+                                if !self.fluid_interior(inner) {
+                                    return None;
+                                }
+                                let diff = if at == 0 {
+                                    p[inner] - value
+                                } else {
+                                    value - p[inner]
+                                };
+                                Some((ghost, vel[ghost] - diff * half_recip[axis] * dt))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .collect()
+            };
+            vel.par_iter_mut().enumerate().for_each(|(i, v)| {
+                if !self.fluid_interior(i) {
+                    return;
+                }
+                if let (Some(lo), Some(hi)) = (side(i, i - stride), side(i, i + stride)) {
+                    *v -= (hi - lo) * half_recip[axis] * dt;
+                }
+            });
+            self.set_bound(&Bound::Velocity(axis), vel);
+            for (ghost, v) in openings {
+                vel[ghost] = v;
+            }
+        }
+
+        report
+    }
+    // How a cell holds pressure during projection, edge cells along periodic axes stand for
+    // the cell across the grid they mirror
+    fn pressure_cell(&self, i: usize) -> Cell<R> {
+        let grid = self.grid;
+        let coords = grid.coords(i);
+        let i = grid.index(array::from_fn(|axis| {
+            let (c, n) = (coords[axis], grid.dim[axis]);
+            match c {
+                0 if self.periodic[axis] => n - 2,
+                c if c == n - 1 && self.periodic[axis] => 1,
+                c => c,
+            }
+        }));
+        if self.solid[i] {
+            return Cell::Wall;
+        }
+        if grid.is_interior(i) {
+            return Cell::Fluid;
+        }
+        match self.edges[i] {
+            EdgeKind::Wall(_) | EdgeKind::MovingWall(_) => Cell::Wall,
+            EdgeKind::Inlet => Cell::Inflow,
+            EdgeKind::Outflow | EdgeKind::ConvectiveOutflow => Cell::Open(R::zero()),
+            EdgeKind::FixedPressure(value) if self.hold_pressure => Cell::Open(R::from_f32(value)),
+            EdgeKind::FixedPressure(_) => Cell::Open(R::zero()),
+        }
+    }
+    /// Moves values along fluids direction of travel
+    pub(crate) fn advect<T, G>(
+        &self,
+        bound: &Bound,
+        vals: &mut [T],
+        vals0: &[T],
+        vel: [&[R]; N],
+        dt: R,
+        params: &FluidParams<G>,
+    ) where
+        T: FieldValue<R>,
+    {
+        let (backtrace, interpolation) = (&params.backtrace, &params.interpolation);
+        match params.advection {
+            AdvectionScheme::SemiLagrangian => {
+                self.semi_lagrangian(vals, vals0, vel, dt, backtrace, interpolation);
+            }
+            AdvectionScheme::MacCormack => {
+                // Advects forward then backward, half the difference from the start
+                // estimates the error made by the forward step
+                self.semi_lagrangian(vals, vals0, vel, dt, backtrace, interpolation);
+                self.set_bound(bound, vals);
+                let mut back = vals0.to_vec();
+                self.semi_lagrangian(&mut back, vals, vel, -dt, backtrace, interpolation);
+
+                vals.par_iter_mut()
+                    .zip(vals0.par_iter().zip(back.par_iter()))
+                    .for_each(|(v, (v0, b))| *v = *v + (*v0 - *b).mul(R::from_f32(0.5)));
+                self.limit(vals, vals0, vel, dt, backtrace);
+            }
+            AdvectionScheme::Bfecc => {
+                // Corrects the starting values by the round trip error before advecting
+                let mut forward = vals0.to_vec();
+                self.semi_lagrangian(&mut forward, vals0, vel, dt, backtrace, interpolation);
+                self.set_bound(bound, &mut forward);
+                let mut back = vals0.to_vec();
+                self.semi_lagrangian(&mut back, &forward, vel, -dt, backtrace, interpolation);
+
+                let mut corrected = vals0.to_vec();
+                corrected
+                    .par_iter_mut()
+                    .zip(back.par_iter())
+                    .for_each(|(c, b)| *c = *c + (*c - *b).mul(R::from_f32(0.5)));
+                self.set_bound(bound, &mut corrected);
+
+                self.semi_lagrangian(vals, &corrected, vel, dt, backtrace, interpolation);
+                self.limit(vals, vals0, vel, dt, backtrace);
+            }
+        }
+
+        self.set_bound(bound, vals);
+    }
+    // Single semi lagrangian step, samples values from where the fluid came from
+    fn semi_lagrangian<T>(
+        &self,
+        vals: &mut [T],
+        vals0: &[T],
+        vel: [&[R]; N],
+        dt: R,
+        backtrace: &Backtrace,
+        interpolation: &Interpolation,
+    ) where
+        T: FieldValue<R>,
+    {
+        vals.par_iter_mut().enumerate().for_each(|(i, v)| {
+            // Convective outflows are carried out by their sim instead
+            if self.edges[i] == EdgeKind::ConvectiveOutflow {
+                *v = vals0[i];
+                return;
+            }
+            let p = self.trace_back(i, vel, dt, backtrace);
+            *v = match interpolation {
+                Interpolation::Bilinear => self.sample(vals0, p),
+                Interpolation::MonotoneCubic => self.sample_cubic(vals0, p),
+            };
+        });
+    }
+    // Clamps values within the range found around where the fluid came from,
+    // keeping higher order schemes from overshooting
+    fn limit<T>(&self, vals: &mut [T], vals0: &[T], vel: [&[R]; N], dt: R, backtrace: &Backtrace)
+    where
+        T: FieldValue<R>,
+    {
+        vals.par_iter_mut().enumerate().for_each(|(i, v)| {
+            if self.edges[i] == EdgeKind::ConvectiveOutflow {
+                return;
+            }
+            let p = self.trace_back(i, vel, dt, backtrace);
+            let (lo, hi) = self.sample_bounds(vals0, p);
+            *v = v.component_max(lo).component_min(hi);
+        });
+    }
+    // Returns the position the fluid in a cell came from dt seconds ago
+    fn trace_back(&self, i: usize, vel: [&[R]; N], dt: R, method: &Backtrace) -> Point<R, N> {
+        let p = Point(self.grid.coords(i).map(R::from_usize));
+        // Velocities are traced in cells per second
+        let vel_at = |p: Point<R, N>| {
+            Point(array::from_fn(|axis| {
+                self.sample(vel[axis], p) / self.spacing[axis]
+            }))
+        };
+        let k1 = Point(array::from_fn(|axis| vel[axis][i] / self.spacing[axis]));
+        method.trace(p, k1, dt, vel_at)
+    }
+    // Moves a coordinate back within the grid, periodic axes wrap around the interior
+    // cells while others are clamped between the edge cells
+    fn wrap(v: R, n: usize, periodic: bool) -> R {
+        if periodic {
+            // Rounding can land exactly on the far edge which is the same place as the first cell
+            let v = R::one() + (v - R::one()).rem_euclid(&R::from_usize(n - 2));
+            if v < R::from_usize(n - 1) {
+                v
+            } else {
+                R::one()
+            }
+        } else {
+            let half = R::from_f32(0.5);
+            v.clamp(half, R::from_usize(n) - half)
+        }
+    }
+    // Returns the cell below a position along each axis and how far past it the position lies
+    fn cell_below(&self, p: Point<R, N>) -> ([usize; N], [R; N]) {
+        let mut base = [0; N];
+        let mut t = [R::zero(); N];
+        for axis in 0..N {
+            let v = Self::wrap(p.0[axis], self.grid.dim[axis], self.periodic[axis]);
+            let v0 = v.floor();
+            base[axis] = v0.as_usize();
+            t[axis] = v - v0;
+        }
+        (base, t)
+    }
+    // Calls f with each of the cells around a position and its weight when interpolating
+    fn for_corners<F: FnMut(usize, R)>(&self, p: Point<R, N>, mut f: F) {
+        let grid = &self.grid;
+        let (base, t) = self.cell_below(p);
+        for corner in 0..1usize << N {
+            let mut i = 0;
+            let mut weight = R::one();
+            for axis in 0..N {
+                let c = if corner >> axis & 1 == 1 {
+                    weight *= t[axis];
+                    (base[axis] + 1).min(grid.dim[axis] - 1)
+                } else {
+                    weight *= R::one() - t[axis];
+                    base[axis]
+                };
+                i += c * grid.strides[axis];
+            }
+            f(i, weight);
+        }
+    }
+    /// Linearly interpolates values at a position along every axis
+    pub(crate) fn sample<T>(&self, vals: &[T], p: Point<R, N>) -> T
+    where
+        T: FieldValue<R>,
+    {
+        let mut total = T::splat(R::zero());
+        self.for_corners(p, |i, w| total = total + vals[i].mul(w));
+        total
+    }
+    // Interpolates values at a position with a Catmull Rom spline, clamped within
+    // the nearest values to avoid overshoot
+    fn sample_cubic<T>(&self, vals: &[T], p: Point<R, N>) -> T
+    where
+        T: FieldValue<R>,
+    {
+        let grid = &self.grid;
+        let (base, t) = self.cell_below(p);
+
+        let c = R::from_f32;
+        let weights = t.map(|t| {
+            let (t2, t3) = (t * t, t * t * t);
+            [
+                c(0.5) * (-t3 + c(2.0) * t2 - t),
+                c(0.5) * (c(3.0) * t3 - c(5.0) * t2 + c(2.0)),
+                c(0.5) * (c(-3.0) * t3 + c(4.0) * t2 + t),
+                c(0.5) * (t3 - t2),
+            ]
+        });
+        // The outermost taps run past the edge cells, periodic axes wrap them back around
+        let tap = |i: usize, n: usize, periodic: bool| {
+            let i = i.saturating_sub(1);
+            if periodic && i >= n {
+                i - (n - 2)
+            } else {
+                i.min(n - 1)
+            }
+        };
+
+        let mut total = T::splat(R::zero());
+        for taps in 0..4usize.pow(N as u32) {
+            let mut i = 0;
+            let mut weight = R::one();
+            let mut rest = taps;
+            for axis in 0..N {
+                let k = rest % 4;
+                rest /= 4;
+                i += tap(base[axis] + k, grid.dim[axis], self.periodic[axis]) * grid.strides[axis];
+                weight *= weights[axis][k];
+            }
+            total = total + vals[i].mul(weight);
+        }
+
+        let (lo, hi) = self.sample_bounds(vals, p);
+        total.component_max(lo).component_min(hi)
+    }
+    // Returns the smallest and largest values around a position
+    fn sample_bounds<T>(&self, vals: &[T], p: Point<R, N>) -> (T, T)
+    where
+        T: FieldValue<R>,
+    {
+        let mut bounds: Option<(T, T)> = None;
+        self.for_corners(p, |i, _| {
+            let v = vals[i];
+            bounds = Some(match bounds {
+                Some((lo, hi)) => (lo.component_min(v), hi.component_max(v)),
+                None => (v, v),
+            });
+        });
+        bounds.expect("a grid position always has corners")
+    }
+}
+
+// Rate fluid spreads out of an interior cell per second by central differences. A solid
+// neighbor counts as the cells own velocity mirrored about the surface, so the flow through
+// the face between them is the velocity of the surface, as at the edge walls
+fn divergence<R: Real, const N: usize>(
+    grid: &Grid<N>,
+    half_recip: &[R; N],
+    solid: &[bool],
+    solid_vel: &[&[R]; N],
+    vel: &[&[R]; N],
+    i: usize,
+) -> R {
+    (0..N).fold(R::zero(), |sum, axis| {
+        let stride = grid.strides[axis];
+        let at = |n: usize| {
+            if solid[n] {
+                solid_vel[axis][n] + solid_vel[axis][n] - vel[axis][i]
+            } else {
+                vel[axis][n]
+            }
+        };
+        sum + (at(i + stride) - at(i - stride)) * half_recip[axis]
+    })
+}
+
+/// Returns the largest divergence in any interior fluid cell per second measured by
+/// central differences over two cells
+pub(crate) fn max_divergence<R: Real, const N: usize>(
+    grid: &Grid<N>,
+    spacing: [R; N],
+    solid: &[bool],
+    solid_vel: [&[R]; N],
+    vel: [&[R]; N],
+) -> R {
+    let half_recip = spacing.map(|d| (d + d).recip());
+    (0..grid.len())
+        .into_par_iter()
+        .filter(|&i| !solid[i] && grid.is_interior(i))
+        .map(|i| divergence(grid, &half_recip, solid, &solid_vel, &vel, i).abs())
+        .reduce(R::zero, R::max)
+}
+
+/// Returns the largest number of cells fluid would cross in a step of dt
+pub(crate) fn cfl<R: Real, const N: usize>(vel: [&[R]; N], spacing: [R; N], dt: R) -> R {
+    (0..vel[0].len())
+        .into_par_iter()
+        .map(|i| {
+            (0..N).fold(R::zero(), |sum, axis| {
+                sum + vel[axis][i].abs() / spacing[axis]
+            })
+        })
+        .reduce(R::zero, R::max)
+        * dt
+}
+
+/// Steps a sim forward by frame_dt in as many substeps as are needed to keep its CFL number
+/// within max_cfl, returning the reports of every substep merged and how many were taken
+pub(crate) fn step_adaptive<S, R: Real>(
+    sim: &mut S,
+    frame_dt: R,
+    max_cfl: f32,
+    max_substeps: usize,
+    cfl: fn(&S, R) -> R,
+    step: fn(&mut S, R) -> StepReport,
+) -> (StepReport, usize) {
+    let mut remaining = frame_dt;
+    let mut report = StepReport::default();
+    let mut substeps = 0;
+    while remaining > R::zero() {
+        substeps += 1;
+        let rate = cfl(sim, R::one());
+        let dt = if substeps >= max_substeps || rate <= R::zero() {
+            remaining
+        } else {
+            (R::from_f32(max_cfl) / rate).min(remaining)
+        };
+        report.merge(step(sim, dt));
+        remaining -= dt;
+    }
+    (report, substeps)
+}
+
+/// Applies gravity using the Boussinesq approximation, the uniform part of gravity is
+/// balanced by pressure so only differences in density and temperature drive the fluid
+/// unless uniform_gravity asks for it too
+pub(crate) fn apply_body_forces<R, G, const N: usize>(
+    vel: [&mut [R]; N],
+    density: &[R::Vec3],
+    temperature: &[R],
+    solid: &[bool],
+    params: &FluidParams<G>,
+    dt: R,
+) where
+    R: Real,
+    G: Copy + Into<[f32; N]>,
+{
+    if !params.uniform_gravity && params.density_buoyancy == 0.0 && params.thermal_buoyancy == 0.0 {
+        return;
+    }
+    let uniform = if params.uniform_gravity {
+        R::one()
+    } else {
+        R::zero()
+    };
+    let buoyancy = R::from_f32(params.density_buoyancy);
+    let thermal = R::from_f32(params.thermal_buoyancy);
+    let ambient = R::from_f32(params.ambient_temperature);
+    let gravity: [f32; N] = params.gravity.into();
+
+    for (vel, g) in vel.into_iter().zip(gravity) {
+        let g = R::from_f32(g) * dt;
+        vel.par_iter_mut()
+            .zip(density.par_iter())
+            .zip(temperature.par_iter())
+            .zip(solid.par_iter())
+            .for_each(|(((v, d), t), solid)| {
+                if !solid {
+                    let [r, g_, b]: [R; 3] = (*d).into();
+                    *v += g
+                        * (uniform + buoyancy * (r + g_ + b) / R::from_f32(3.0)
+                            - thermal * (*t - ambient));
+                }
+            });
+    }
+}
+
+/// Pushes fluid around the centers of swirls to keep them from being smoothed away
+///
+/// The curl is kept as its component within each plane of two axes, a single value in two
+/// dimensions and the three components of the curl vector in three
+pub(crate) fn confine_vorticity<R: Real, const N: usize>(
+    grid: &Grid<N>,
+    spacing: [R; N],
+    solid: &[bool],
+    vel: [&mut [R]; N],
+    strength: f32,
+    dt: R,
+) {
+    if strength == 0.0 {
+        return;
+    }
+    let half_recip = spacing.map(|d| (d + d).recip());
+    let fluid_interior = |i: usize| !solid[i] && grid.is_interior(i);
+    let planes: Vec<(usize, usize)> = (0..N)
+        .flat_map(|a| (a + 1..N).map(move |b| (a, b)))
+        .collect();
+    // Rate the difference in velocity across a cell along a changes along b
+    let gradient = |v: &[R], i: usize, axis: usize| {
+        let stride = grid.strides[axis];
+        (v[i + stride] - v[i - stride]) * half_recip[axis]
+    };
+
+    // Curl within each plane and its size at every cell
+    let curl: Vec<Vec<R>> = {
+        let vel = vel.each_ref().map(|v| &**v);
+        planes
+            .iter()
+            .map(|&(a, b)| {
+                (0..grid.len())
+                    .into_par_iter()
+                    .map(|i| {
+                        if !fluid_interior(i) {
+                            return R::zero();
+                        }
+                        gradient(vel[b], i, a) - gradient(vel[a], i, b)
+                    })
+                    .collect()
+            })
+            .collect()
+    };
+    let size: Vec<R> = (0..grid.len())
+        .into_par_iter()
+        .map(|i| curl.iter().map(|c| c[i] * c[i]).sum::<R>().sqrt())
+        .collect();
+    let plane_curl = |i: usize, a: usize, b: usize| match planes.iter().position(|&p| p == (a, b)) {
+        Some(plane) => curl[plane][i],
+        None => -curl[planes.iter().position(|&p| p == (b, a)).unwrap()][i],
+    };
+
+    let scale = R::from_f32(strength) * dt * spacing.iter().copied().fold(R::infinity(), R::min);
+    let force: Vec<[R; N]> = (0..grid.len())
+        .into_par_iter()
+        .map(|i| {
+            if !fluid_interior(i) {
+                return [R::zero(); N];
+            }
+            // Direction towards the center of the swirl
+            let towards: [R; N] = array::from_fn(|axis| gradient(&size, i, axis));
+            let length_recip = towards.iter().map(|g| *g * *g).sum::<R>().sqrt().recip();
+            if !length_recip.is_finite() {
+                return [R::zero(); N];
+            }
+            array::from_fn(|a| {
+                (0..N)
+                    .filter(|&b| b != a)
+                    .map(|b| towards[b] * length_recip * plane_curl(i, a, b))
+                    .sum::<R>()
+                    * scale
+            })
+        })
+        .collect();
+
+    for (axis, vel) in vel.into_iter().enumerate() {
+        vel.par_iter_mut()
+            .zip(force.par_iter())
+            .for_each(|(v, f)| *v += f[axis]);
+    }
+}
@@ -4,6 +4,18 @@
 
 /// A grid holding velocities and density of particles within fluid
 pub mod flow_box;
+/// A three dimensional grid of velocities and density, viewed a slice at a time
+pub mod flow_box3;
 /// An object capable of displaying a FlowBox with different modes and settings
 /// Also offers simple and convenient functions to interact with fluid
 pub mod flow_display;
+/// Grids along any number of axes and the solver stages FlowBox and FlowBox3 share
+mod grid;
+/// A grid holding velocities on the faces between cells for accurate incompressibility
+pub mod mac_box;
+/// Solid bodies which can be placed and moved within a FlowBox
+pub mod obstacle;
+/// Solvers for the pressure equation which keeps fluid from compressing
+pub mod pressure;
+/// Floating point precisions the solvers can run at
+pub mod real;
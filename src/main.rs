@@ -22,7 +22,7 @@ async fn main() {
         let pos = flow_display.get_mouse_cord(&flow_box.dim);
         let angle = flow_display.get_mouse_mov_dir();
 
-        flow_box.add_fluid_velocity_angle_mag(pos.0, pos.1, angle, 10000.0);
+        flow_box.add_fluid_velocity_angle_mag(pos.0, pos.1, angle, 2.0);
         flow_box.add_fluid_density(
             pos.0,
             pos.1,
@@ -30,7 +30,7 @@ async fn main() {
         );
 
         // Simulating and drawing
-        flow_box.step(1.0 / 30.0);
+        flow_box.step_adaptive(1.0 / 30.0);
         flow_display.display(&flow_box);
 
         // Next frame
@@ -0,0 +1,83 @@
+//! Defines solid bodies which move through a FlowBox pushing fluid out of the way
+
+use super::flow_box::Slip;
+use glam::Vec2;
+
+/// Outline of an obstacle, measured in grid cells around its position
+pub enum Shape {
+    // Circle with the given radius
+    Circle(f32),
+    // Rectangle with the given half width and half height
+    Rect(f32, f32),
+    // Polygon with points given relative to the obstacles position
+    Polygon(Vec<Vec2>),
+}
+impl Shape {
+    /// Returns whether a point relative to the shapes center lies within it
+    pub fn contains(&self, p: Vec2) -> bool {
+        match self {
+            Shape::Circle(radius) => p.length_squared() <= radius * radius,
+            Shape::Rect(half_w, half_h) => p.x.abs() <= *half_w && p.y.abs() <= *half_h,
+            Shape::Polygon(points) => polygon_contains(points, p),
+        }
+    }
+}
+
+/// A kinematic obstacle, its motion is prescribed rather than driven by the fluid
+pub struct Obstacle {
+    pub shape: Shape,
+    // Position of the obstacles center in grid cells
+    pub position: Vec2,
+    // Velocity in metres per second
+    pub velocity: Vec2,
+    // Rotation of the shape in radians
+    pub angle: f32,
+    // Rotation speed in radians per second
+    pub angular_velocity: f32,
+    // How fluid moves along the obstacles surface
+    pub slip: Slip,
+}
+impl Obstacle {
+    pub fn init(shape: Shape, position: Vec2) -> Self {
+        Obstacle {
+            shape,
+            position,
+            velocity: Vec2::ZERO,
+            angle: 0.0,
+            angular_velocity: 0.0,
+            slip: Slip::NoSlip,
+        }
+    }
+    /// Moves and rotates the obstacle forward in time through cells of the given width and
+    /// height in metres
+    pub fn advance(&mut self, dt: f32, spacing: Vec2) {
+        self.position += self.velocity / spacing * dt;
+        self.angle += self.angular_velocity * dt;
+    }
+    /// Returns whether a point in grid space lies within the obstacle
+    pub fn contains(&self, p: Vec2) -> bool {
+        let local = Vec2::from_angle(-self.angle).rotate(p - self.position);
+        self.shape.contains(local)
+    }
+    /// Returns the velocity in metres per second of the obstacles body at a point in grid
+    /// space, with cells of the given width and height in metres
+    pub fn surface_velocity(&self, p: Vec2, spacing: Vec2) -> Vec2 {
+        let r = p - self.position;
+        self.velocity + Vec2::new(-r.y, r.x) * self.angular_velocity * spacing
+    }
+}
+
+/// Even odd rule point in polygon test
+pub(crate) fn polygon_contains(points: &[Vec2], p: Vec2) -> bool {
+    let mut inside = false;
+    let mut j = points.len().wrapping_sub(1);
+    for (i, pi) in points.iter().enumerate() {
+        let pj = points[j];
+        if (pi.y > p.y) != (pj.y > p.y) && p.x < (pj.x - pi.x) * (p.y - pi.y) / (pj.y - pi.y) + pi.x
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
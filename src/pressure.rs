@@ -0,0 +1,976 @@
+//! Defines solvers for the pressure Poisson equation used to remove divergence
+//!
+//! Pressure is solved on a grid of cells using the compact Laplacian, 5 points in two
+//! dimensions and 7 in three, or the wide Laplacian which couples cells two apart so it
+//! matches gradients and divergence taken by central differences. Only fluid cells are
+//! unknowns, the cells around them act as walls, inflows or openings at a fixed pressure.
+//! Cells may be spaced differently along each axis, each neighbor is weighted by one over
+//! the square of its distance, and periodic axes connect the first and last cells along them
+
+use std::borrow::Cow;
+
+use rayon::prelude::*;
+
+use super::grid::Grid;
+use super::real::Real;
+
+/// Methods for solving for pressure during projection
+#[derive(PartialEq, Clone, Copy)]
+pub enum PressureSolver {
+    // A fixed number of relaxation sweeps set by project_iters
+    Relaxation,
+    // Preconditioned conjugate gradient, runs until the residual falls below pressure_tolerance
+    ConjugateGradient(Preconditioner),
+    // Geometric multigrid cycles, runs until the residual falls below pressure_tolerance
+    Multigrid(Cycle),
+}
+
+/// Preconditioners for the conjugate gradient solver
+#[derive(PartialEq, Clone, Copy)]
+pub enum Preconditioner {
+    // Divides by the diagonal, cheap and parallel
+    Jacobi,
+    // Modified incomplete Cholesky, far fewer iterations but applied serially
+    IncompleteCholesky,
+}
+
+/// Order coarser grids are visited in each multigrid cycle
+#[derive(PartialEq, Clone, Copy)]
+pub enum Cycle {
+    // Visits each coarser grid once on the way down and once on the way up
+    V,
+    // Visits each coarser grid twice, more work per cycle but fewer cycles
+    W,
+}
+
+/// Outcome of an iterative solve
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SolveReport {
+    // Iterations or multigrid cycles used
+    pub iterations: usize,
+    // Largest remaining residual relative to the largest value being solved for
+    pub residual: f32,
+}
+
+impl SolveReport {
+    /// Builds a report, scaling the residual by the largest value being solved for
+    pub(crate) fn relative<R: Real>(iterations: usize, residual: R, scale: R) -> Self {
+        SolveReport {
+            iterations,
+            residual: if scale > R::zero() {
+                residual / scale
+            } else {
+                residual
+            }
+            .as_f32(),
+        }
+    }
+}
+
+/// When an iterative solve stops, whichever limit is reached first
+#[derive(Clone, Copy)]
+pub(crate) struct SolveLimits {
+    pub(crate) max_iters: usize,
+    // Largest residual relative to the largest value being solved for, zero never stops early
+    pub(crate) tolerance: f32,
+}
+
+/// Red black Gauss Seidel sweeps before and after visiting a coarser grid
+const MG_SMOOTH_SWEEPS: usize = 2;
+/// Sweeps used to solve the coarsest grid
+const MG_COARSE_SWEEPS: usize = 40;
+/// Grids are not coarsened below this many cells on a side
+const MG_MIN_SIZE: usize = 4;
+
+/// Tuning constant blending incomplete Cholesky towards modified incomplete Cholesky
+const MIC_TAU: f32 = 0.97;
+/// Falls back to the plain diagonal when the factorization gets too close to zero
+const MIC_SIGMA: f32 = 0.25;
+
+/// What fills a cell of the grid pressure is solved over
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Cell<R> {
+    // Pressure is unknown and solved for
+    Fluid,
+    // Flow into it is reflected like a wall or solid, pressure has zero gradient across it
+    Wall,
+    // Flow through it is set like an inlet, pressure does not push on it
+    Inflow,
+    // Pressure is held at the given value like an outflow
+    Open(R),
+}
+
+// Where a cell couples to along one side of an axis
+enum Link<R> {
+    Cell(usize),
+    Fixed(R),
+}
+
+/// A grid within the multigrid hierarchy
+struct Level<R, const N: usize> {
+    grid: Grid<N>,
+    cells: Vec<Cell<R>>,
+    open: Vec<R>,
+    // Neighbors of each cell along with their weights
+    rows: Vec<Vec<(usize, R)>>,
+}
+
+/// The Laplacian over the fluid cells of a grid
+pub(crate) struct Poisson<'a, R: Real, const N: usize> {
+    grid: Grid<N>,
+    cells: &'a [Cell<R>],
+    // Weight of neighbors along each axis
+    weights: [R; N],
+    // Whether each axis wraps around
+    periodic: [bool; N],
+    // How many cells apart neighbors sharing a pressure difference are, 1 for the compact
+    // Laplacian and 2 for the wide one
+    reach: usize,
+    // Weight of the fixed pressures bordering each cell
+    open: Cow<'a, [R]>,
+    // Fixed pressures bordering each cell times their weights, empty when none do
+    fixed: Vec<R>,
+    // Neighbors of each cell when given outright as on coarser grids, empty when they are
+    // found by walking from the cell
+    rows: &'a [Vec<(usize, R)>],
+}
+impl<'a, R: Real, const N: usize> Poisson<'a, R, N> {
+    /// Creates the compact Laplacian for cells spaced by spacing along each axis
+    pub(crate) fn new(
+        grid: Grid<N>,
+        cells: &'a [Cell<R>],
+        spacing: [R; N],
+        periodic: [bool; N],
+    ) -> Self {
+        Poisson {
+            grid,
+            cells,
+            weights: spacing.map(|d| (d * d).recip()),
+            periodic,
+            reach: 1,
+            open: Cow::Borrowed(&[]),
+            fixed: Vec::new(),
+            rows: &[],
+        }
+        .border()
+    }
+    /// Couples cells reach apart instead of neighbors, 2 gives the wide Laplacian which is
+    /// the divergence of the central difference gradient
+    pub(crate) fn with_reach(mut self, reach: usize) -> Self {
+        let scale = R::from_usize(self.reach * self.reach) / R::from_usize(reach * reach);
+        self.weights = self.weights.map(|w| w * scale);
+        self.reach = reach;
+        self.border()
+    }
+    // Finds the fixed pressures bordering each fluid cell
+    fn border(mut self) -> Self {
+        let (open, fixed): (Vec<R>, Vec<R>) = (0..self.cells.len())
+            .into_par_iter()
+            .map(|i| {
+                if !self.is_fluid(i) {
+                    return (R::zero(), R::zero());
+                }
+                self.links(i)
+                    .fold((R::zero(), R::zero()), |(o, f), (link, w)| match link {
+                        Link::Fixed(v) => (o + w, f + w * v),
+                        Link::Cell(_) => (o, f),
+                    })
+            })
+            .unzip();
+        self.open = Cow::Owned(open);
+        self.fixed = fixed;
+        self
+    }
+    /// Solves `sum(w (p - neighbor p)) = b` for every fluid cell, using p as the first guess.
+    /// Stops once the largest residual is within tolerance of the largest value of b, or
+    /// down to the rounding error of summing the neighbors
+    pub(crate) fn solve_cg(
+        &self,
+        p: &mut [R],
+        b: &[R],
+        preconditioner: &Preconditioner,
+        limits: &SolveLimits,
+    ) -> SolveReport {
+        let n = p.len();
+
+        let b = self.rhs(b);
+        let scale = Self::max_abs(&b);
+
+        let mut r = vec![R::zero(); n];
+        self.residual(p, &b, &mut r);
+        let mut report = SolveReport::relative(0, Self::max_abs(&r), scale);
+        if Self::max_abs(&r) <= self.target(p, scale, limits) {
+            return report;
+        }
+
+        let precon = match preconditioner {
+            Preconditioner::Jacobi => Vec::new(),
+            Preconditioner::IncompleteCholesky => self.mic_factor(),
+        };
+        let mut z = vec![R::zero(); n];
+        self.precondition(preconditioner, &precon, &r, &mut z);
+        let mut s = z.clone();
+        let mut sigma = Self::dot(&z, &r);
+        let mut q = vec![R::zero(); n];
+
+        for iteration in 1..=limits.max_iters {
+            self.apply(&s, &mut q);
+            let alpha = sigma / Self::dot(&s, &q);
+            if !alpha.is_finite() {
+                break;
+            }
+            p.par_iter_mut()
+                .zip(r.par_iter_mut())
+                .zip(s.par_iter().zip(q.par_iter()))
+                .for_each(|((p, r), (s, q))| {
+                    *p += alpha * *s;
+                    *r -= alpha * *q;
+                });
+            let mut residual = Self::max_abs(&r);
+            if residual <= self.target(p, scale, limits) {
+                // Rounding lets the updated residual drift from b - A p, so it is measured
+                // afresh before stopping and the search carries on from it if still too large
+                self.residual(p, &b, &mut r);
+                residual = Self::max_abs(&r);
+            }
+            report = SolveReport::relative(iteration, residual, scale);
+            if residual <= self.target(p, scale, limits) {
+                break;
+            }
+
+            self.precondition(preconditioner, &precon, &r, &mut z);
+            let sigma_new = Self::dot(&z, &r);
+            let beta = sigma_new / sigma;
+            s.par_iter_mut()
+                .zip(z.par_iter())
+                .for_each(|(s, z)| *s = *z + beta * *s);
+            sigma = sigma_new;
+        }
+        report
+    }
+    /// Solves the same system as solve_cg with multigrid cycles
+    pub(crate) fn solve_multigrid(
+        &self,
+        p: &mut [R],
+        b: &[R],
+        cycle: &Cycle,
+        limits: &SolveLimits,
+    ) -> SolveReport {
+        let b = self.rhs(b);
+        let scale = Self::max_abs(&b);
+        let levels = self.coarsen();
+
+        let mut r = vec![R::zero(); p.len()];
+        let mut report = SolveReport::default();
+        for iteration in 0..=limits.max_iters {
+            if iteration > 0 {
+                self.cycle(&levels, p, &b, cycle);
+            }
+            self.residual(p, &b, &mut r);
+            let residual = Self::max_abs(&r);
+            report = SolveReport::relative(iteration, residual, scale);
+            if residual <= self.target(p, scale, limits) {
+                break;
+            }
+        }
+        report
+    }
+    // Residual the iterative solvers stop at. Summing neighbors of size p leaves rounding
+    // errors no solver can remove, so when b is near zero, as in a steady flow whose last
+    // pressure is the first guess, the solve stops there rather than chasing the noise
+    fn target(&self, p: &[R], scale: R, limits: &SolveLimits) -> R {
+        let weight = self.weights.iter().copied().sum::<R>();
+        let rounding = R::epsilon() * R::from_f32(2.0) * weight * Self::max_abs(p);
+        (R::from_f32(limits.tolerance) * scale).max(rounding)
+    }
+    // Measures how far p is from solving the system for b, relative to the largest value of b
+    fn relative_residual(&self, p: &[R], b: &[R]) -> f32 {
+        let b = self.rhs(b);
+        let mut r = vec![R::zero(); p.len()];
+        self.residual(p, &b, &mut r);
+        SolveReport::relative(0, Self::max_abs(&r), Self::max_abs(&b)).residual
+    }
+    // Runs one multigrid cycle, correcting p using coarser grids
+    fn cycle(&self, coarser: &[Level<R, N>], p: &mut [R], b: &[R], cycle: &Cycle) {
+        let one = R::one();
+        let Some((next, rest)) = coarser.split_first() else {
+            self.smooth(p, b, MG_COARSE_SWEEPS, one);
+            return;
+        };
+
+        self.smooth(p, b, MG_SMOOTH_SWEEPS, one);
+
+        // Sums the residual of each block of 2 cells along every axis onto the coarser grid
+        let mut r = vec![R::zero(); p.len()];
+        self.residual(p, b, &mut r);
+        let mut coarse_b = vec![R::zero(); next.cells.len()];
+        for (i, r) in r.iter().enumerate() {
+            if self.is_fluid(i) {
+                coarse_b[self.parent(i, &next.grid)] += *r;
+            }
+        }
+
+        let coarse = Poisson {
+            grid: next.grid,
+            cells: &next.cells,
+            weights: self
+                .weights
+                .map(|w| w * R::from_usize(1 << N) / R::from_f32(4.0)),
+            periodic: self.periodic,
+            reach: self.reach,
+            open: Cow::Borrowed(&next.open),
+            fixed: Vec::new(),
+            rows: &next.rows,
+        };
+        let mut coarse_p = vec![R::zero(); next.cells.len()];
+        let visits = match cycle {
+            Cycle::V => 1,
+            Cycle::W => 2,
+        };
+        for _ in 0..visits {
+            coarse.cycle(rest, &mut coarse_p, &coarse_b, cycle);
+        }
+
+        p.par_iter_mut().enumerate().for_each(|(i, p)| {
+            if self.is_fluid(i) {
+                *p += coarse_p[self.parent(i, &next.grid)];
+            }
+        });
+
+        self.smooth(p, b, MG_SMOOTH_SWEEPS, one);
+    }
+    /// Relaxes p with red black Gauss Seidel sweeps, over relaxed by omega, until the
+    /// residual is within tolerance
+    pub(crate) fn solve_relaxation(
+        &self,
+        p: &mut [R],
+        b: &[R],
+        omega: R,
+        limits: &SolveLimits,
+    ) -> SolveReport {
+        let rhs = self.rhs(b);
+        let mut report = SolveReport::default();
+        for iteration in 1..=limits.max_iters {
+            self.smooth(p, &rhs, 1, omega);
+            report.iterations = iteration;
+            if limits.tolerance > 0.0 {
+                report.residual = self.relative_residual(p, b);
+                if report.residual <= limits.tolerance {
+                    return report;
+                }
+            }
+        }
+        report.residual = self.relative_residual(p, b);
+        report
+    }
+    // Red black Gauss Seidel, colored by blocks of reach cells so most neighbors have the
+    // other color. Each half sweep reads a copy taken before it so it can update in parallel
+    fn smooth(&self, p: &mut [R], b: &[R], sweeps: usize, omega: R) {
+        let mut other = p.to_vec();
+        for _ in 0..sweeps {
+            for color in 0..2 {
+                other.copy_from_slice(p);
+                p.par_iter_mut().enumerate().for_each(|(i, p)| {
+                    let parity: usize = self.grid.coords(i).iter().map(|c| c / self.reach).sum();
+                    if !self.is_fluid(i) || parity % 2 != color {
+                        return;
+                    }
+                    let (sum, diag) = self
+                        .neighbors(i)
+                        .fold((R::zero(), self.open(i)), |(s, d), (j, w)| {
+                            (s + w * other[j], d + w)
+                        });
+                    if diag > R::zero() {
+                        *p += omega * ((b[i] + sum) / diag - *p);
+                    }
+                });
+            }
+        }
+    }
+    // Builds the hierarchy of ever coarser grids, a coarse cell is fluid if any of the
+    // finer cells in its block of 2 along every axis are. The wide Laplacian couples cells
+    // of the same parity so blocks are taken from cells reach apart.
+    //
+    // Two coarse cells are linked by half the weights linking the finer cells they cover.
+    // Between blocks of 2 along every axis 2^(N-1) links cross each face, each a quarter of
+    // the coarse weight over twice the distance, while summed residuals stand in for 2^N
+    // times the average, so halving gives the same equation. Summing the links rather than
+    // walking the coarse grid keeps the links around walls which tie cells of different
+    // parity together, without them the wide Laplacian splits apart on coarse grids
+    fn coarsen(&self) -> Vec<Level<R, N>> {
+        let half = R::from_f32(0.5);
+        let mut levels: Vec<Level<R, N>> = Vec::new();
+        loop {
+            let (grid, cells, open) = match levels.last() {
+                Some(level) => (level.grid, &level.cells[..], &level.open[..]),
+                None => (self.grid, self.cells, &self.open[..]),
+            };
+            if grid.dim.iter().any(|&n| n <= MG_MIN_SIZE * self.reach) {
+                return levels;
+            }
+            let coarse = Grid::new(grid.dim.map(|n| self.up(n - 1) + 1));
+            let parent = |i: usize| coarse.index(grid.coords(i).map(|c| self.up(c)));
+            let mut coarse_cells = vec![Cell::Wall; coarse.len()];
+            let mut rows: Vec<Vec<(usize, R)>> = vec![Vec::new(); coarse.len()];
+            for (i, cell) in cells.iter().enumerate() {
+                if *cell != Cell::Fluid {
+                    continue;
+                }
+                let at = parent(i);
+                coarse_cells[at] = Cell::Fluid;
+                let mut link = |j: usize, w: R| {
+                    let to = parent(j);
+                    if to == at {
+                        return;
+                    }
+                    match rows[at].iter_mut().find(|(k, _)| *k == to) {
+                        Some((_, total)) => *total += w * half,
+                        None => rows[at].push((to, w * half)),
+                    }
+                };
+                match levels.last() {
+                    Some(level) => level.rows[i].iter().for_each(|&(j, w)| link(j, w)),
+                    None => self.neighbors(i).for_each(|(j, w)| link(j, w)),
+                }
+            }
+            // A coarse cell borders the fixed pressure through each of its finer cells which
+            // do, their weights add up just as the coarse equation sums the finer ones.
+            // Taking any less lets corrections near outflows overshoot and diverge
+            let mut coarse_open = vec![R::zero(); coarse_cells.len()];
+            for (i, o) in open.iter().enumerate() {
+                coarse_open[parent(i)] += *o;
+            }
+            levels.push(Level {
+                grid: coarse,
+                cells: coarse_cells,
+                open: coarse_open,
+                rows,
+            });
+        }
+    }
+    // Coordinate of the coarser cell covering a coordinate along an axis
+    fn up(&self, c: usize) -> usize {
+        c / (2 * self.reach) * self.reach + c % self.reach
+    }
+    // Index of the coarser cell covering a cell
+    fn parent(&self, i: usize, coarse: &Grid<N>) -> usize {
+        coarse.index(self.grid.coords(i).map(|c| self.up(c)))
+    }
+    // Residual b - A p
+    fn residual(&self, p: &[R], b: &[R], r: &mut [R]) {
+        self.apply(p, r);
+        r.par_iter_mut()
+            .zip(b.par_iter())
+            .enumerate()
+            .for_each(|(i, (r, b))| *r = if self.is_fluid(i) { *b - *r } else { R::zero() });
+    }
+    // Multiplies p by the Laplacian
+    fn apply(&self, p: &[R], out: &mut [R]) {
+        out.par_iter_mut().enumerate().for_each(|(i, o)| {
+            *o = if self.is_fluid(i) {
+                self.neighbors(i)
+                    .fold(self.open(i) * p[i], |total, (j, w)| {
+                        total + w * (p[i] - p[j])
+                    })
+            } else {
+                R::zero()
+            };
+        });
+    }
+    fn is_fluid(&self, i: usize) -> bool {
+        self.cells[i] == Cell::Fluid
+    }
+    // Cell a step from i along one side of an axis, wrapping around periodic axes and None
+    // past the edge of the grid
+    fn step(&self, i: usize, axis: usize, forward: bool) -> Option<usize> {
+        let (n, stride) = (self.grid.dim[axis], self.grid.strides[axis]);
+        let wrap = self.periodic[axis];
+        let c = self.grid.coords(i)[axis];
+        if forward {
+            (c + 1 < n)
+                .then(|| i + stride)
+                .or_else(|| wrap.then(|| i - (n - 1) * stride))
+        } else {
+            (c > 0)
+                .then(|| i - stride)
+                .or_else(|| wrap.then(|| i + (n - 1) * stride))
+        }
+    }
+    // Where a fluid cell couples to along one side of an axis. The wide Laplacian follows
+    // the central differences, the pressure difference across a cell spans its neighbors
+    // on both sides. A wall takes the pressure of the cell beside it, so a wall next to the
+    // cell brings back the cell on its other side and a wall past the neighbor brings back
+    // the neighbor, while an inflow has no pressure difference across it at all
+    fn link(&self, i: usize, axis: usize, forward: bool) -> Option<Link<R>> {
+        // Past the edge of the grid is a wall
+        let kind = |j: Option<usize>| j.map_or(Cell::Wall, |j| self.cells[j]);
+        let reach_to = |j: Option<usize>| match kind(j) {
+            Cell::Fluid => j.map(Link::Cell),
+            Cell::Open(v) => Some(Link::Fixed(v)),
+            Cell::Wall | Cell::Inflow => None,
+        };
+        let next = self.step(i, axis, forward);
+        if self.reach == 1 {
+            return reach_to(next);
+        }
+        match kind(next) {
+            Cell::Fluid => {
+                let far = next.and_then(|j| self.step(j, axis, forward));
+                match kind(far) {
+                    Cell::Wall => next.map(Link::Cell),
+                    _ => reach_to(far),
+                }
+            }
+            Cell::Wall => reach_to(self.step(i, axis, !forward)),
+            Cell::Inflow => None,
+            Cell::Open(v) => Some(Link::Fixed(v)),
+        }
+    }
+    // Everything a cell couples to along with the weights
+    fn links(&self, i: usize) -> impl Iterator<Item = (Link<R>, R)> + '_ {
+        (0..N).flat_map(move |axis| {
+            [false, true]
+                .into_iter()
+                .filter_map(move |forward| self.link(i, axis, forward))
+                .map(move |link| (link, self.weights[axis]))
+        })
+    }
+    // Fluid neighbors of a cell along with their weights, a cell brought back to itself
+    // adds nothing to the Laplacian
+    fn neighbors(&self, i: usize) -> impl Iterator<Item = (usize, R)> + '_ {
+        let walked = self.rows.is_empty().then(|| {
+            self.links(i).filter_map(move |(link, w)| match link {
+                Link::Cell(j) if j != i => Some((j, w)),
+                _ => None,
+            })
+        });
+        let given = self.rows.get(i).into_iter().flatten().copied();
+        given.chain(walked.into_iter().flatten())
+    }
+    // Total weight of fluid neighbors, the diagonal of the Laplacian
+    fn diagonal(&self, i: usize) -> R {
+        self.open(i) + self.neighbors(i).map(|(_, w)| w).sum::<R>()
+    }
+    // Weight of the fixed pressure bordering a cell
+    fn open(&self, i: usize) -> R {
+        self.open.get(i).copied().unwrap_or(R::zero())
+    }
+    // Off diagonal entries of the Laplacian in the row of a cell, a neighbor linked along
+    // several sides has their weights added
+    fn couplings(&self, i: usize) -> Vec<(usize, R)> {
+        let mut couplings: Vec<(usize, R)> = Vec::new();
+        for (j, w) in self.neighbors(i) {
+            match couplings.iter_mut().find(|(k, _)| *k == j) {
+                Some((_, c)) => *c -= w,
+                None => couplings.push((j, -w)),
+            }
+        }
+        couplings
+    }
+    // Factors the Laplacian into modified incomplete Cholesky form, cells earlier in memory
+    // are eliminated first
+    fn mic_factor(&self) -> Vec<R> {
+        let mut precon = vec![R::zero(); self.cells.len()];
+        for i in 0..self.cells.len() {
+            if !self.is_fluid(i) {
+                continue;
+            }
+            let diag = self.diagonal(i);
+            let mut e = diag;
+            for (u, coupling) in self.couplings(i).into_iter().filter(|&(u, _)| u < i) {
+                let a = coupling * precon[u];
+                let others = self
+                    .couplings(u)
+                    .into_iter()
+                    .filter(|&(j, _)| j > u && j != i)
+                    .map(|(_, c)| c)
+                    .sum::<R>();
+                e -= a * a + R::from_f32(MIC_TAU) * coupling * others * precon[u] * precon[u];
+            }
+            if e < R::from_f32(MIC_SIGMA) * diag {
+                e = diag;
+            }
+            precon[i] = if e > R::zero() {
+                e.sqrt().recip()
+            } else {
+                R::zero()
+            };
+        }
+        precon
+    }
+    // Applies the inverse of the preconditioner to r
+    fn precondition(&self, preconditioner: &Preconditioner, precon: &[R], r: &[R], z: &mut [R]) {
+        match preconditioner {
+            Preconditioner::Jacobi => {
+                z.par_iter_mut().enumerate().for_each(|(i, z)| {
+                    let d = self.diagonal(i);
+                    *z = if self.is_fluid(i) && d > R::zero() {
+                        r[i] / d
+                    } else {
+                        R::zero()
+                    };
+                });
+            }
+            Preconditioner::IncompleteCholesky => {
+                let n = r.len();
+                // Forward substitution
+                let mut q = vec![R::zero(); n];
+                for i in 0..n {
+                    if !self.is_fluid(i) {
+                        continue;
+                    }
+                    let mut t = r[i];
+                    for (u, w) in self.neighbors(i).filter(|&(u, _)| u < i) {
+                        t += w * precon[u] * q[u];
+                    }
+                    q[i] = t * precon[i];
+                }
+                // Backward substitution
+                for i in (0..n).rev() {
+                    if !self.is_fluid(i) {
+                        z[i] = R::zero();
+                        continue;
+                    }
+                    let mut t = q[i];
+                    for (j, w) in self.neighbors(i).filter(|&(j, _)| j > i) {
+                        t += w * precon[i] * z[j];
+                    }
+                    z[i] = t * precon[i];
+                }
+            }
+        }
+    }
+    // Returns b plus the fixed pressures bordering each cell. Unless some cells border a
+    // fixed pressure which pins the solution down, walls all around leave pressure only
+    // known up to a constant, so any net inflow has no solution and its average is removed
+    fn rhs(&self, b: &[R]) -> Vec<R> {
+        let b: Vec<R> = b
+            .par_iter()
+            .enumerate()
+            .map(|(i, b)| {
+                if self.is_fluid(i) {
+                    *b + self.fixed.get(i).copied().unwrap_or(R::zero())
+                } else {
+                    R::zero()
+                }
+            })
+            .collect();
+        if self.open.iter().any(|o| *o > R::zero()) {
+            return b;
+        }
+        let (sum, count) = b
+            .par_iter()
+            .enumerate()
+            .filter(|&(i, _)| self.is_fluid(i))
+            .map(|(_, b)| (b.as_f64(), 1usize))
+            .reduce(|| (0.0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+        let mean = if count > 0 {
+            R::from_f64(sum / count as f64)
+        } else {
+            R::zero()
+        };
+        b.par_iter()
+            .enumerate()
+            .map(|(i, b)| {
+                if self.is_fluid(i) {
+                    *b - mean
+                } else {
+                    R::zero()
+                }
+            })
+            .collect()
+    }
+    fn dot(a: &[R], b: &[R]) -> R {
+        let sum = a
+            .par_iter()
+            .zip(b.par_iter())
+            .map(|(a, b)| a.as_f64() * b.as_f64())
+            .sum::<f64>();
+        R::from_f64(sum)
+    }
+    fn max_abs(a: &[R]) -> R {
+        a.par_iter().map(|a| a.abs()).reduce(R::zero, R::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOLVERS: [PressureSolver; 4] = [
+        PressureSolver::ConjugateGradient(Preconditioner::Jacobi),
+        PressureSolver::ConjugateGradient(Preconditioner::IncompleteCholesky),
+        PressureSolver::Multigrid(Cycle::V),
+        PressureSolver::Multigrid(Cycle::W),
+    ];
+
+    const LIMITS: SolveLimits = SolveLimits {
+        max_iters: 500,
+        tolerance: 1e-4,
+    };
+
+    // A small Poisson problem around a solid block, with each way the edges can close it
+    struct Problem {
+        grid: Grid<2>,
+        cells: Vec<Cell<f32>>,
+        periodic: [bool; 2],
+        b: Vec<f32>,
+    }
+    impl Problem {
+        fn closed() -> Self {
+            let grid = Grid::new([24, 20]);
+            let cells = (0..grid.len())
+                .map(|i| {
+                    let [x, y] = grid.coords(i);
+                    if (8..12).contains(&x) && (6..14).contains(&y) {
+                        Cell::Wall
+                    } else {
+                        Cell::Fluid
+                    }
+                })
+                .collect();
+            let b = (0..grid.len())
+                .map(|i| {
+                    let [x, y] = grid.coords(i);
+                    (x as f32 * 0.7).sin() + (y as f32 * 0.4).cos()
+                })
+                .collect();
+            Problem {
+                grid,
+                cells,
+                periodic: [false; 2],
+                b,
+            }
+        }
+        fn periodic() -> Self {
+            Problem {
+                periodic: [true, false],
+                ..Self::closed()
+            }
+        }
+        // The left column is held at zero pressure
+        fn dirichlet() -> Self {
+            let mut problem = Self::closed();
+            for (i, cell) in problem.cells.iter_mut().enumerate() {
+                if problem.grid.coords(i)[0] == 0 {
+                    *cell = Cell::Open(0.0);
+                }
+            }
+            problem
+        }
+        // Wraps along y with the left column held at a fixed pressure and an inflow along
+        // the right, so every kind of cell borders the fluid
+        fn mixed() -> Self {
+            let mut problem = Self::closed();
+            for (i, cell) in problem.cells.iter_mut().enumerate() {
+                match problem.grid.coords(i)[0] {
+                    0 => *cell = Cell::Open(0.5),
+                    23 => *cell = Cell::Inflow,
+                    _ => {}
+                }
+            }
+            Problem {
+                periodic: [false, true],
+                ..problem
+            }
+        }
+        fn all() -> [Self; 4] {
+            [
+                Self::closed(),
+                Self::periodic(),
+                Self::dirichlet(),
+                Self::mixed(),
+            ]
+        }
+        fn poisson(&self) -> Poisson<'_, f32, 2> {
+            Poisson::new(self.grid, &self.cells, [1.0; 2], self.periodic)
+        }
+    }
+
+    fn solve<const N: usize>(
+        poisson: &Poisson<f32, N>,
+        solver: &PressureSolver,
+        p: &mut [f32],
+        b: &[f32],
+        limits: &SolveLimits,
+    ) -> SolveReport {
+        match solver {
+            PressureSolver::Relaxation => poisson.solve_relaxation(p, b, 1.0, limits),
+            PressureSolver::ConjugateGradient(preconditioner) => {
+                poisson.solve_cg(p, b, preconditioner, limits)
+            }
+            PressureSolver::Multigrid(cycle) => poisson.solve_multigrid(p, b, cycle, limits),
+        }
+    }
+
+    #[test]
+    fn converged_guess_stops_at_once_when_b_is_noise() {
+        // A steady flow leaves only rounding noise in b while the last pressure, used as the
+        // first guess, is large and already solves the system
+        let grid = Grid::new([32, 32]);
+        let cells = vec![Cell::Fluid; grid.len()];
+        let poisson = Poisson::new(grid, &cells, [1.0; 2], [false; 2]);
+        let b: Vec<f32> = (0..cells.len())
+            .map(|i| if i % 2 == 0 { 1e-6 } else { -1e-6 })
+            .collect();
+        let limits = SolveLimits {
+            max_iters: 500,
+            tolerance: 1e-4,
+        };
+
+        for solver in SOLVERS {
+            let mut p = vec![1000.0; cells.len()];
+            assert_eq!(solve(&poisson, &solver, &mut p, &b, &limits).iterations, 0);
+        }
+    }
+
+    // Solves each problem from a zero guess, checking the residual is really within
+    // tolerance and the solver took no more than the given iterations
+    fn assert_converges(solver: PressureSolver, max_iterations: usize) {
+        for problem in Problem::all() {
+            let poisson = problem.poisson();
+            let mut p = vec![0.0; problem.cells.len()];
+            let report = solve(&poisson, &solver, &mut p, &problem.b, &LIMITS);
+            assert!(report.iterations <= max_iterations);
+            assert!(report.residual <= LIMITS.tolerance);
+            assert_eq!(report.residual, poisson.relative_residual(&p, &problem.b));
+        }
+    }
+
+    #[test]
+    fn cg_converges() {
+        assert_converges(
+            PressureSolver::ConjugateGradient(Preconditioner::Jacobi),
+            120,
+        );
+        assert_converges(
+            PressureSolver::ConjugateGradient(Preconditioner::IncompleteCholesky),
+            30,
+        );
+    }
+
+    #[test]
+    fn mic_factor_covers_fluid_cells() {
+        for problem in Problem::all() {
+            let poisson = problem.poisson();
+            let precon = poisson.mic_factor();
+            let first = problem
+                .cells
+                .iter()
+                .position(|c| *c == Cell::Fluid)
+                .unwrap();
+            assert_eq!(precon[first], poisson.diagonal(first).sqrt().recip());
+            for (c, e) in problem.cells.iter().zip(precon) {
+                if *c == Cell::Fluid {
+                    assert!(e.is_finite() && e > 0.0);
+                } else {
+                    assert_eq!(e, 0.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn multigrid_converges() {
+        assert_converges(PressureSolver::Multigrid(Cycle::V), 20);
+        assert_converges(PressureSolver::Multigrid(Cycle::W), 10);
+    }
+
+    #[test]
+    fn coarsen_halves_down_to_min_size() {
+        let problem = Problem::dirichlet();
+        let poisson = problem.poisson();
+        let levels = poisson.coarsen();
+        let dims: Vec<_> = levels.iter().map(|level| level.grid.dim).collect();
+        assert_eq!(dims, [[12, 10], [6, 5], [3, 3]]);
+
+        // A coarse cell is fluid when any cell it covers is, so only the middle of the
+        // solid block stays solid
+        let level = &levels[0];
+        for (i, c) in level.cells.iter().enumerate() {
+            let [x, y] = level.grid.coords(i);
+            assert_eq!(
+                *c == Cell::Fluid,
+                !((4..6).contains(&x) && (3..7).contains(&y))
+            );
+        }
+        // Openings add up so each level borders the fixed pressure as strongly
+        for level in &levels {
+            assert_eq!(level.open.iter().sum::<f32>(), 20.0);
+        }
+    }
+
+    #[test]
+    fn laplacian_is_symmetric() {
+        for problem in Problem::all() {
+            for reach in [1, 2] {
+                let poisson = problem.poisson().with_reach(reach);
+                let n = problem.cells.len();
+                let column = |j: usize| {
+                    let mut unit = vec![0.0; n];
+                    unit[j] = 1.0;
+                    let mut out = vec![0.0; n];
+                    poisson.apply(&unit, &mut out);
+                    out
+                };
+                let columns: Vec<Vec<f32>> = (0..n).map(column).collect();
+                for i in (0..n).filter(|&i| poisson.is_fluid(i)) {
+                    for j in (0..n).filter(|&j| poisson.is_fluid(j)) {
+                        assert_eq!(columns[j][i], columns[i][j]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn solvers_converge_on_the_wide_laplacian() {
+        // Cells of each parity only meet at walls so relaxation needs far more sweeps
+        let limits = SolveLimits {
+            max_iters: 1500,
+            ..LIMITS
+        };
+        let solvers = [PressureSolver::Relaxation].into_iter().chain(SOLVERS);
+        for (solver, max_iterations) in solvers.zip([1500, 80, 30, 10, 8]) {
+            for problem in Problem::all() {
+                let poisson = problem.poisson().with_reach(2);
+                let mut p = vec![0.0; problem.cells.len()];
+                let report = solve(&poisson, &solver, &mut p, &problem.b, &limits);
+                assert!(report.iterations <= max_iterations);
+                assert!(report.residual <= limits.tolerance);
+                assert_eq!(report.residual, poisson.relative_residual(&p, &problem.b));
+            }
+        }
+    }
+
+    #[test]
+    fn solvers_converge_in_three_dimensions() {
+        // A box around a solid block which wraps along z
+        let grid = Grid::new([20, 18, 16]);
+        let cells: Vec<Cell<f32>> = (0..grid.len())
+            .map(|i| {
+                let [x, y, z] = grid.coords(i);
+                if (6..10).contains(&x) && (5..11).contains(&y) && (4..10).contains(&z) {
+                    Cell::Wall
+                } else {
+                    Cell::Fluid
+                }
+            })
+            .collect();
+        let b: Vec<f32> = (0..grid.len())
+            .map(|i| {
+                let [x, y, z] = grid.coords(i);
+                (x as f32 * 0.7).sin() + (y as f32 * 0.4).cos() + (z as f32 * 0.9).sin()
+            })
+            .collect();
+        let poisson = Poisson::new(grid, &cells, [1.0; 3], [false, false, true]);
+
+        for (solver, max_iterations) in SOLVERS.into_iter().zip([120, 40, 10, 8]) {
+            let mut p = vec![0.0; grid.len()];
+            let report = solve(&poisson, &solver, &mut p, &b, &LIMITS);
+            assert!(report.iterations <= max_iterations);
+            assert!(report.residual <= LIMITS.tolerance);
+            assert_eq!(report.residual, poisson.relative_residual(&p, &b));
+        }
+    }
+}
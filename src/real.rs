@@ -0,0 +1,192 @@
+//! Defines the floating point precisions the solvers can run at
+use std::fmt::Debug;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, MulAssign, Sub};
+
+use glam::{DVec2, DVec3, Vec2, Vec3};
+use num_traits::{Euclid, Float, NumAssign};
+
+/// Values which can be stored on the grid and carried around by the fluid, scaled by R
+pub trait FieldValue<R>:
+    Copy + Add<Output = Self> + Sub<Output = Self> + Mul<R, Output = Self> + Send + Sync
+{
+    /// Creates a value with every component set to v
+    fn splat(v: R) -> Self;
+    /// Component wise minimum
+    fn component_min(self, other: Self) -> Self;
+    /// Component wise maximum
+    fn component_max(self, other: Self) -> Self;
+    /// Largest absolute component
+    fn magnitude(self) -> R;
+}
+
+/// A scalar type the solver runs at, f32 for interactive use and f64 for validation
+pub trait Real:
+    Float + NumAssign + Euclid + FieldValue<Self> + Sum + Default + Debug + 'static
+{
+    /// Two component vector of this precision, used for positions and cell spacing
+    type Vec2: FieldValue<Self>
+        + Debug
+        + PartialEq
+        + Mul<Output = Self::Vec2>
+        + Div<Output = Self::Vec2>
+        + From<[Self; 2]>
+        + Into<[Self; 2]>;
+    /// Three component vector of this precision, used for dye color
+    type Vec3: FieldValue<Self>
+        + AddAssign
+        + MulAssign<Self>
+        + Debug
+        + PartialEq
+        + From<[Self; 3]>
+        + Into<[Self; 3]>;
+
+    fn from_f32(v: f32) -> Self;
+    fn from_f64(v: f64) -> Self;
+    fn from_usize(v: usize) -> Self;
+    fn as_f32(self) -> f32;
+    fn as_f64(self) -> f64;
+    /// Truncates towards zero like an `as` cast, negative values and NaN become 0
+    fn as_usize(self) -> usize;
+
+    /// Converts a single precision vector such as a velocity from the settings
+    fn vec2(v: Vec2) -> Self::Vec2 {
+        v.to_array().map(Self::from_f32).into()
+    }
+    /// Converts a single precision vector such as a dye color from the settings
+    fn vec3(v: Vec3) -> Self::Vec3 {
+        v.to_array().map(Self::from_f32).into()
+    }
+}
+
+impl FieldValue<f32> for f32 {
+    fn splat(v: f32) -> Self {
+        v
+    }
+    fn component_min(self, other: Self) -> Self {
+        f32::min(self, other)
+    }
+    fn component_max(self, other: Self) -> Self {
+        f32::max(self, other)
+    }
+    fn magnitude(self) -> f32 {
+        self.abs()
+    }
+}
+impl FieldValue<f64> for f64 {
+    fn splat(v: f64) -> Self {
+        v
+    }
+    fn component_min(self, other: Self) -> Self {
+        f64::min(self, other)
+    }
+    fn component_max(self, other: Self) -> Self {
+        f64::max(self, other)
+    }
+    fn magnitude(self) -> f64 {
+        self.abs()
+    }
+}
+impl FieldValue<f32> for Vec2 {
+    fn splat(v: f32) -> Self {
+        Vec2::splat(v)
+    }
+    fn component_min(self, other: Self) -> Self {
+        Vec2::min(self, other)
+    }
+    fn component_max(self, other: Self) -> Self {
+        Vec2::max(self, other)
+    }
+    fn magnitude(self) -> f32 {
+        self.abs().max_element()
+    }
+}
+impl FieldValue<f64> for DVec2 {
+    fn splat(v: f64) -> Self {
+        DVec2::splat(v)
+    }
+    fn component_min(self, other: Self) -> Self {
+        DVec2::min(self, other)
+    }
+    fn component_max(self, other: Self) -> Self {
+        DVec2::max(self, other)
+    }
+    fn magnitude(self) -> f64 {
+        self.abs().max_element()
+    }
+}
+impl FieldValue<f32> for Vec3 {
+    fn splat(v: f32) -> Self {
+        Vec3::splat(v)
+    }
+    fn component_min(self, other: Self) -> Self {
+        Vec3::min(self, other)
+    }
+    fn component_max(self, other: Self) -> Self {
+        Vec3::max(self, other)
+    }
+    fn magnitude(self) -> f32 {
+        self.abs().max_element()
+    }
+}
+impl FieldValue<f64> for DVec3 {
+    fn splat(v: f64) -> Self {
+        DVec3::splat(v)
+    }
+    fn component_min(self, other: Self) -> Self {
+        DVec3::min(self, other)
+    }
+    fn component_max(self, other: Self) -> Self {
+        DVec3::max(self, other)
+    }
+    fn magnitude(self) -> f64 {
+        self.abs().max_element()
+    }
+}
+
+impl Real for f32 {
+    type Vec2 = Vec2;
+    type Vec3 = Vec3;
+
+    fn from_f32(v: f32) -> Self {
+        v
+    }
+    fn from_f64(v: f64) -> Self {
+        v as f32
+    }
+    fn from_usize(v: usize) -> Self {
+        v as f32
+    }
+    fn as_f32(self) -> f32 {
+        self
+    }
+    fn as_f64(self) -> f64 {
+        self as f64
+    }
+    fn as_usize(self) -> usize {
+        self as usize
+    }
+}
+impl Real for f64 {
+    type Vec2 = DVec2;
+    type Vec3 = DVec3;
+
+    fn from_f32(v: f32) -> Self {
+        v as f64
+    }
+    fn from_f64(v: f64) -> Self {
+        v
+    }
+    fn from_usize(v: usize) -> Self {
+        v as f64
+    }
+    fn as_f32(self) -> f32 {
+        self as f32
+    }
+    fn as_f64(self) -> f64 {
+        self
+    }
+    fn as_usize(self) -> usize {
+        self as usize
+    }
+}